@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+use rand::seq::SliceRandom;
+use crate::Address;
+
+pub struct ChokeCandidate {
+    pub addr: Address,
+    //Bytes per second the peer sent us
+    pub download_rate: u64,
+    //Bytes per second we sent the peer
+    pub upload_rate: u64,
+    pub interested: bool
+}
+
+pub trait Choker: Send {
+    //Called every choke round with every connected peer, returns the peers to unchoke; all others get choked
+    fn unchoke(&mut self, peers: &[ChokeCandidate], seeding: bool) -> HashSet<Address>;
+}
+
+//Unchokes the fastest interested peers plus one optimistic unchoke rotated every few rounds
+pub struct TitForTat {
+    slots: usize,
+    optimistic_rounds: u32,
+    round: u32,
+    optimistic: Option<Address>
+}
+
+impl TitForTat {
+    pub fn new(slots: usize, optimistic_rounds: u32) -> Self {
+        Self {
+            slots,
+            optimistic_rounds,
+            round: 0,
+            optimistic: None
+        }
+    }
+}
+
+impl Choker for TitForTat {
+    fn unchoke(&mut self, peers: &[ChokeCandidate], seeding: bool) -> HashSet<Address> {
+        let mut interested: Vec<&ChokeCandidate> = peers.iter().filter(|p| p.interested).collect();
+        //Leeching rewards peers that upload to us, seeding favours peers that can take our data fastest
+        if seeding {
+            interested.sort_by_key(|p| std::cmp::Reverse(p.upload_rate));
+        } else {
+            interested.sort_by_key(|p| std::cmp::Reverse(p.download_rate));
+        }
+
+        let mut unchoked: HashSet<Address> = interested.iter()
+            .take(self.slots.saturating_sub(1))
+            .map(|p| p.addr)
+            .collect();
+
+        let optimistic_gone = match self.optimistic {
+            Some(addr) => unchoked.contains(&addr) || !interested.iter().any(|p| p.addr == addr),
+            None => true
+        };
+        if optimistic_gone || self.round == 0 {
+            let choked: Vec<Address> = interested.iter().map(|p| p.addr).filter(|addr| !unchoked.contains(addr)).collect();
+            self.optimistic = choked.choose(&mut rand::thread_rng()).copied();
+        }
+        self.round = (self.round + 1) % self.optimistic_rounds.max(1);
+
+        if let Some(addr) = self.optimistic {
+            unchoked.insert(addr);
+        }
+        unchoked
+    }
+}
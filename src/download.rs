@@ -1,8 +1,20 @@
-use std::{net::SocketAddr, sync::Arc, time::Instant};
+use std::{collections::VecDeque, sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}}, time::{Duration, Instant}};
+use bencode::BeeValue;
 use sha1::{Sha1, Digest};
-use tokio::sync::{mpsc::Sender, broadcast::Receiver};
-use tokio::{net::TcpStream, io::{AsyncWriteExt, AsyncReadExt}};
-use crate::{message::{builders, parse, BitfieldMessage, PieceMessage}, torrent_parser::Torrent, queue::PieceQueue, piece::{Piece, PieceWrite}, Address};
+use tokio::sync::{mpsc::{self, Sender}, broadcast::Receiver, watch};
+use tokio::{net::TcpStream, io::{AsyncWriteExt, AsyncReadExt}, time::{self, interval_at}};
+use crate::{message::{builders, parse, BitfieldMessage, ExtendedMessage, HaveMessage, PieceMessage, RequestMessage}, torrent_parser::Torrent, queue::{BlockResult, PieceQueue}, piece::PieceWrite, storage::Storage, peers::{TorrentContext, TransferStats}, choker::ChokeCandidate, hasher::Hasher, Address};
+
+//Largest block we serve, bigger requests are ignored
+const MAX_REQUEST_LEN: i32 = 131072;
+//Bounds of the outstanding request window, the peer's reqq lowers the upper bound
+const MIN_QUEUE_DEPTH: usize = 2;
+const MAX_QUEUE_DEPTH: usize = 250;
+//The adaptive window holds this many seconds worth of blocks at the measured rate
+const REQUEST_QUEUE_TIME: f64 = 3.0;
+const RATE_SAMPLE: Duration = Duration::from_secs(1);
+//Peers drop connections that stay silent for about two minutes
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
 
 #[derive(Debug, Clone)]
 pub enum Status {
@@ -13,33 +25,133 @@ pub enum Status {
     Halted
 }
 
+#[derive(Debug, Clone)]
+pub enum PeerCommand {
+    Choke,
+    Unchoke,
+    //We verified this piece
+    Have(usize)
+}
+
+//Counters shared between a peer task and the choker
+#[derive(Default)]
+pub struct PeerStats {
+    pub downloaded: AtomicU64,
+    pub uploaded: AtomicU64,
+    pub interested: AtomicBool,
+    //We dialed the peer and it answered the handshake, so the address is worth keeping for the next run
+    pub reachable: AtomicBool
+}
+
+pub struct PeerHandle {
+    pub addr: Address,
+    pub stats: Arc<PeerStats>,
+    pub commands: mpsc::UnboundedSender<PeerCommand>,
+    last_downloaded: u64,
+    last_uploaded: u64
+}
+
+impl PeerHandle {
+    //Rates since the previous call, which is expected elapsed ago
+    pub fn candidate(&mut self, elapsed: Duration) -> ChokeCandidate {
+        let downloaded = self.stats.downloaded.load(Ordering::Relaxed);
+        let uploaded = self.stats.uploaded.load(Ordering::Relaxed);
+        let secs = elapsed.as_secs().max(1);
+        let candidate = ChokeCandidate {
+            addr: self.addr,
+            download_rate: (downloaded - self.last_downloaded) / secs,
+            upload_rate: (uploaded - self.last_uploaded) / secs,
+            interested: self.stats.interested.load(Ordering::Relaxed)
+        };
+        self.last_downloaded = downloaded;
+        self.last_uploaded = uploaded;
+        candidate
+    }
+}
+
 pub struct Peer {
     worker: Arc<PieceQueue>,
-    piece: Option<Piece>,
+    storage: Arc<dyn Storage>,
+    stats: Arc<TransferStats>,
+    hasher: Hasher,
+    //Blocks requested from this peer that haven't arrived, (piece, block)
+    outstanding: Vec<(usize, usize)>,
+    //Set when the queue had nothing for this peer, cleared once it changes
+    starved: bool,
+    //Fixed request window, adaptive when None
+    request_queue: Option<usize>,
+    //Most outstanding requests the peer accepts, from its extended handshake
+    reqq: usize,
+    download_rate: f64,
+    rate_sample_start: Instant,
+    rate_sample_bytes: u64,
     choked: bool,
+    am_choking: bool,
+    //Both sides set the fast extension bit (BEP 6)
+    fast: bool,
+    //Our bitfield went out, until then nothing else may be sent
+    handshake_done: bool,
+    //We dialed the peer, inbound peers connect from ephemeral ports
+    outbound: bool,
+    requests: VecDeque<RequestMessage>,
     last_piece: Instant,
     bitfield: Vec<bool>,
     piece_sender: Sender<PieceWrite>,
     status_receiver: Receiver<Status>,
+    command_receiver: mpsc::UnboundedReceiver<PeerCommand>,
+    work_changed: watch::Receiver<u64>,
+    endgame_blocks: Receiver<(usize, usize)>,
+    suppress_haves: bool,
+    rates: Arc<PeerStats>,
     status: Status,
     addr: Address,
     torrent: Arc<Torrent>
 }
 
 impl Peer {
-    pub fn new(addr: Address, worker: Arc<PieceQueue>, piece_sender: Sender<PieceWrite>, status_receiver: Receiver<Status>, status: Status, torrent: Arc<Torrent>) -> Self {
-        Peer {
-            worker,
+    pub fn new(addr: Address, context: TorrentContext, piece_sender: Sender<PieceWrite>, status_receiver: Receiver<Status>, status: Status) -> (Self, PeerHandle) {
+        //Unbounded so a burst of completed pieces never drops a have
+        let (command_sender, command_receiver) = mpsc::unbounded_channel::<PeerCommand>();
+        let rates = Arc::new(PeerStats::default());
+        let handle = PeerHandle {
+            addr,
+            stats: rates.clone(),
+            commands: command_sender,
+            last_downloaded: 0,
+            last_uploaded: 0
+        };
+        let peer = Peer {
+            work_changed: context.work_queue.subscribe(),
+            endgame_blocks: context.work_queue.subscribe_blocks(),
+            worker: context.work_queue,
+            storage: context.storage,
+            stats: context.stats,
+            hasher: context.hasher,
             choked: false,
+            am_choking: true,
+            fast: false,
+            handshake_done: false,
+            outbound: false,
+            requests: VecDeque::new(),
             bitfield: Vec::new(),
-            piece: None,
+            outstanding: Vec::new(),
+            starved: false,
+            request_queue: context.request_queue,
+            reqq: MAX_QUEUE_DEPTH,
+            download_rate: 0.0,
+            rate_sample_start: Instant::now(),
+            rate_sample_bytes: 0,
             last_piece: Instant::now(),
             piece_sender,
             status_receiver,
-            torrent,
+            command_receiver,
+            suppress_haves: context.suppress_haves,
+            rates,
+            torrent: context.torrent,
             status,
             addr
-        }
+        };
+        (peer, handle)
     }
 
     fn exit_socket(&mut self, socket: &mut TcpStream) {
@@ -48,14 +160,30 @@ impl Peer {
     }
 
     fn exit(&mut self) {
-        if self.piece.is_some() {
-            self.worker.push(self.piece.as_ref().unwrap().piece_index as usize, self.piece.as_ref().unwrap().frequency)
+        //Received blocks stay in the shared piece table, only the requests are handed back
+        for (piece_index, block_index) in self.outstanding.drain(..) {
+            self.worker.unrequest(piece_index, block_index, self.addr)
         }
+        self.worker.remove(&self.bitfield);
+        self.bitfield.clear();
     }
 
     async fn on_socket(&mut self, msg: &[u8], socket: &mut TcpStream) -> bool {
         if is_handshake(msg) {
-            let _ = socket.write_all(&builders::build_interested()).await;
+            self.fast = msg[27] & 0x04 != 0;
+            if !self.send_pieces(socket).await {
+                return false
+            }
+            self.handshake_done = true;
+            if self.outbound {
+                self.rates.reachable.store(true, Ordering::Relaxed);
+            }
+            if let Status::Leeching = self.status {
+                let _ = socket.write_all(&builders::build_interested()).await;
+            }
+        } else if msg.len() == 4 {
+            //Keep-alive
+            return true
         } else {
             let m = match parse(msg) {
                 Some(a) => a,
@@ -65,40 +193,84 @@ impl Peer {
             match m.id {
                 0 => self.choke_handler(),
                 1 => self.unchoke_handler(socket).await,
-                5 => return self.bitfield_handler(&m.bitfield_message.unwrap()),
+                2 => self.rates.interested.store(true, Ordering::Relaxed),
+                3 => self.rates.interested.store(false, Ordering::Relaxed),
+                4 => return self.have_handler(socket, m.have_message).await,
+                5 => return self.bitfield_handler(socket, &m.bitfield_message.unwrap()).await,
+                6 => return self.request_handler(socket, m.request_message).await,
                 7 => return self.piece_handler(socket, &m.piece_message.unwrap()).await,
+                8 => return self.cancel_handler(m.request_message),
+                14 => return self.bitfield_handler(socket, &BitfieldMessage { bitfield: vec![true; self.torrent.num_pieces] }).await,
+                15 => {
+                    self.worker.remove(&self.bitfield);
+                    self.bitfield = vec![false; self.torrent.num_pieces];
+                },
+                16 => return self.reject_handler(socket, m.request_message).await,
+                20 => return self.extended_handler(m.extended_message),
                 _ => return true,
             }
         }
         true
     }
 
-    fn set_status(&mut self, status: Status, socket: &mut TcpStream) {
+    async fn set_status(&mut self, status: Status, socket: &mut TcpStream) {
         self.status = status;
         match self.status {
-            Status::Closing => return self.exit_socket(socket),
+            Status::Closing => self.exit_socket(socket),
             Status::Leeching => todo!(),
-            Status::Seeding => todo!(),
+            Status::Seeding => {
+                let _ = socket.write_all(&builders::build_uninterested()).await;
+            },
             Status::Peering => todo!(),
             Status::Halted => todo!(),
         }
     }
 
     pub async fn connect(&mut self) {
-        let mut socket = match TcpStream::connect(SocketAddr::from(self.addr.to_owned())).await {
+        self.outbound = true;
+        let mut socket = match TcpStream::connect(self.addr).await {
             Ok(s) => s,
             Err(_) => {
                 return self.exit();
             }
         };
         
+        socket.write_all(&builders::build_handshake(&self.torrent.info_hash())).await.unwrap();
+        self.run(socket).await
+    }
+
+    //Inbound connection, the listener has already read and validated the peer's handshake
+    pub async fn accept(&mut self, mut socket: TcpStream, handshake: &[u8]) {
+        if socket.write_all(&builders::build_handshake(&self.torrent.info_hash())).await.is_err()
+            || !self.on_socket(handshake, &mut socket).await {
+            return self.exit_socket(&mut socket);
+        }
+        self.run(socket).await
+    }
+
+    //Tells the peer which pieces we have, must directly follow the handshake
+    async fn send_pieces(&mut self, socket: &mut TcpStream) -> bool {
+        let completed = self.worker.completed_count();
+        let msg = if self.fast && completed == self.torrent.num_pieces {
+            builders::build_have_all()
+        } else if self.fast && completed == 0 {
+            builders::build_have_none()
+        } else if completed == 0 {
+            //Without the fast extension an empty bitfield may be omitted
+            return true
+        } else {
+            builders::build_bitfield(&self.worker.bitfield(self.torrent.num_pieces))
+        };
+        socket.write_all(&msg).await.is_ok()
+    }
+
+    async fn run(&mut self, mut socket: TcpStream) {
         let mut buffer: Vec<u8> = Vec::new();
         let mut temp_buffer: [u8; 65536] = [0; 65536];
         let mut current_size: i32 = 0;
         
-        socket.write_all(&builders::build_handshake(&self.torrent)).await.unwrap();
         self.last_piece = Instant::now();
-        
+        let mut keep_alive = interval_at(time::Instant::now() + KEEP_ALIVE_INTERVAL, KEEP_ALIVE_INTERVAL);
         
         loop {
             tokio::select! {
@@ -113,7 +285,7 @@ impl Peer {
                     current_size += size;
                     
                     while current_size > 0 { 
-                        if self.last_piece.elapsed().as_secs() > 10 {
+                        if self.is_waiting_for_blocks() && self.last_piece.elapsed().as_secs() > 10 {
                             return self.exit_socket(&mut socket);
                         }
                         if let Some(packet_size) = get_packet_size(&buffer) {
@@ -133,11 +305,37 @@ impl Peer {
                         
                         return self.exit_socket(&mut socket);
                     }
+
+                    if !self.serve_requests(&mut socket).await {
+                        return self.exit_socket(&mut socket);
+                    }
                 },
                 
                 status = self.status_receiver.recv() => {
                     if let Ok(status) = status {
-                        self.set_status(status, &mut socket)
+                        self.set_status(status, &mut socket).await
+                    }
+                },
+
+                //Commands wait in the channel until the bitfield is sent, a have must not come first
+                Some(command) = self.command_receiver.recv(), if self.handshake_done => {
+                    if !self.command_handler(command, &mut socket).await {
+                        return self.exit_socket(&mut socket);
+                    }
+                },
+
+                //Another peer released a piece or a peer announced new ones
+                Ok(_) = self.work_changed.changed(), if self.is_idle() => self.request_blocks(&mut socket).await,
+
+                Ok((piece_index, block_index)) = self.endgame_blocks.recv(), if self.handshake_done => {
+                    if !self.received_elsewhere(&mut socket, piece_index, block_index).await {
+                        return self.exit_socket(&mut socket);
+                    }
+                },
+
+                _ = keep_alive.tick(), if self.handshake_done => {
+                    if socket.write_all(&builders::build_keep_alive()).await.is_err() {
+                        return self.exit_socket(&mut socket);
                     }
                 }
             }
@@ -146,70 +344,286 @@ impl Peer {
 
     fn choke_handler(&mut self) {
         self.choked = true;
+        //Without the fast extension a choke silently drops every pending request
+        if !self.fast {
+            for (piece_index, block_index) in self.outstanding.drain(..) {
+                self.worker.unrequest(piece_index, block_index, self.addr);
+            }
+        }
     }
 
     async fn unchoke_handler(&mut self, socket: &mut TcpStream) {
         self.choked = false;
-        self.request_piece(socket).await;
+        self.request_blocks(socket).await;
+    }
+
+    //Leeching peers that found nothing in the queue wait for it to change
+    fn is_idle(&self) -> bool {
+        matches!(self.status, Status::Leeching) && self.starved && !self.choked
     }
 
-    fn pop_piece(&mut self) -> bool {
-        let (piece, freq) = self.worker.pop(&is_available, &self.bitfield);
-        self.piece = Some(Piece::new(piece as i32, freq, &self.torrent));
+    //Enough requests to cover REQUEST_QUEUE_TIME at the current rate, like libtorrent
+    fn queue_depth(&self) -> usize {
+        let max = self.reqq.clamp(1, MAX_QUEUE_DEPTH);
+        match self.request_queue {
+            Some(depth) => depth.clamp(1, max),
+            None => ((self.download_rate * REQUEST_QUEUE_TIME / 16384.0) as usize).clamp(MIN_QUEUE_DEPTH.min(max), max)
+        }
+    }
+
+    //Fills the request window from the shared piece table
+    async fn request_blocks(&mut self, socket: &mut TcpStream) {
+        self.starved = false;
+        if !matches!(self.status, Status::Leeching) || self.choked {
+            return
+        }
+
+        let depth = self.queue_depth();
+        while self.outstanding.len() < depth {
+            let (piece_index, block_index) = match self.worker.request_block(&is_available, &self.bitfield, self.addr, &self.torrent) {
+                Some(block) => block,
+                None => {
+                    self.starved = true;
+                    return
+                }
+            };
+            if self.outstanding.is_empty() {
+                self.last_piece = Instant::now();
+            }
+            self.outstanding.push((piece_index, block_index));
+
+            let piece_index = piece_index as i32;
+            let block_len = self.torrent.block_len(piece_index, block_index as i32) as u32;
+            let _ = socket.write_all(&builders::build_request(piece_index, (block_index * 16384) as i32, block_len)).await;
+        }
+    }
+
+    //Cancels what is still in flight for a piece that was completed
+    async fn cancel_piece(&mut self, socket: &mut TcpStream, piece_index: usize) -> bool {
+        let cancelled: Vec<usize> = self.outstanding.iter().filter(|(p, _)| *p == piece_index).map(|(_, b)| *b).collect();
+        self.outstanding.retain(|(p, _)| *p != piece_index);
+        for block_index in cancelled {
+            if !self.send_cancel(socket, piece_index as i32, block_index).await {
+                return false
+            }
+        }
         true
     }
 
-    fn bitfield_handler(&mut self, bitfield_message: &BitfieldMessage) -> bool {
-        self.bitfield = Vec::with_capacity(bitfield_message.bitfield.len());
-        for (i, b) in bitfield_message.bitfield.iter().enumerate() {
+    async fn send_cancel(&mut self, socket: &mut TcpStream, piece_index: i32, block_index: usize) -> bool {
+        let block_len = self.torrent.block_len(piece_index, block_index as i32);
+        socket.write_all(&builders::build_cancel(piece_index, (block_index * 16384) as i32, block_len)).await.is_ok()
+    }
+
+    fn extended_handler(&mut self, extended: Option<ExtendedMessage>) -> bool {
+        let extended = match extended {
+            Some(e) => e,
+            None => return false
+        };
+        //Extended handshake
+        if extended.id == 0 {
+            let handshake = BeeValue::from_bytes(&extended.payload);
+            let reqq = handshake.get_dict().and_then(|dict| dict.get("reqq").cloned()).and_then(|reqq| reqq.get_int());
+            if let Some(reqq) = reqq.and_then(|reqq| usize::try_from(reqq).ok()) {
+                self.reqq = reqq;
+            }
+        }
+        true
+    }
+
+    async fn bitfield_handler(&mut self, socket: &mut TcpStream, bitfield_message: &BitfieldMessage) -> bool {
+        //The last byte is padded, spare bits are ignored
+        self.worker.remove(&self.bitfield);
+        self.bitfield = bitfield_message.bitfield.iter().take(self.torrent.num_pieces).copied().collect();
+        self.bitfield.resize(self.torrent.num_pieces, false);
+        for (i, b) in self.bitfield.iter().enumerate() {
             if *b {
                 self.worker.push(i, 1);
             }
-            self.bitfield.push(*b);
         }
-        
-        self.pop_piece()
+        self.request_blocks(socket).await;
+        true
     }
 
-    async fn piece_handler(&mut self, socket: &mut TcpStream, piece_resp: &PieceMessage) -> bool {
-        let piece = self.piece.as_mut().unwrap();
-        let completed = piece.add_block((piece_resp.block_begin/16384) as usize, piece_resp.block.clone());
-        
-        if completed {
-            let piece_write = PieceWrite {
-                data: piece.blocks.as_ref().unwrap().iter().flat_map(|a| a.clone()).collect(),
-                piece_index: piece.piece_index as usize,
-            };
-            
-            if is_correct(&piece_write, &self.torrent) {
-                if self.piece_sender.send(piece_write).await.is_err() {
+    async fn have_handler(&mut self, socket: &mut TcpStream, have: Option<HaveMessage>) -> bool {
+        let piece_index = match have {
+            Some(h) if h.piece_index >= 0 && (h.piece_index as usize) < self.torrent.num_pieces => h.piece_index as usize,
+            _ => return false
+        };
+        //Peers may skip the bitfield when they have nothing
+        if self.bitfield.is_empty() {
+            self.bitfield = vec![false; self.torrent.num_pieces];
+        }
+        if self.bitfield[piece_index] {
+            return true
+        }
+        self.bitfield[piece_index] = true;
+        self.worker.push(piece_index, 1);
+
+        //The peer may have had nothing we wanted so far
+        self.request_blocks(socket).await;
+        true
+    }
+
+    async fn command_handler(&mut self, command: PeerCommand, socket: &mut TcpStream) -> bool {
+        match command {
+            PeerCommand::Choke if !self.am_choking => {
+                self.am_choking = true;
+                if socket.write_all(&builders::build_choke()).await.is_err() {
                     return false
                 }
-            } else {
-                self.worker.push(piece.piece_index as usize, piece.frequency);
-                if !self.pop_piece() {
+                //With the fast extension choking no longer discards requests implicitly
+                while let Some(request) = self.requests.pop_front() {
+                    if !self.reject(socket, &request).await {
+                        return false
+                    }
+                }
+                true
+            },
+            PeerCommand::Unchoke if self.am_choking => {
+                self.am_choking = false;
+                socket.write_all(&builders::build_unchoke()).await.is_ok()
+            },
+            PeerCommand::Have(piece_index) => {
+                //Endgame duplicates of the piece are no longer needed
+                if !self.cancel_piece(socket, piece_index).await {
                     return false
                 }
-
-                self.last_piece = Instant::now();
-    
-                if !self.choked {
-                    self.request_piece(socket).await;
+                //Have suppression: the peer will never request a piece it already has
+                if self.suppress_haves && is_available(piece_index, &self.bitfield) {
+                    return true
                 }
-            }
+                socket.write_all(&builders::build_have(piece_index as u32)).await.is_ok()
+            },
+            _ => true
         }
+    }
+
+    async fn request_handler(&mut self, socket: &mut TcpStream, request: Option<RequestMessage>) -> bool {
+        let request = match request {
+            Some(r) => r,
+            None => return false
+        };
+        if self.am_choking || !self.can_serve(&request) {
+            return self.reject(socket, &request).await
+        }
+        self.requests.push_back(request);
         true
     }
 
-    async fn request_piece(&mut self, socket: &mut TcpStream) {
-        if self.choked || self.piece.is_none() {
-            return
+    async fn reject(&mut self, socket: &mut TcpStream, request: &RequestMessage) -> bool {
+        if !self.fast {
+            return true
+        }
+        socket.write_all(&builders::build_reject(request.piece_index, request.block_begin, request.block_length)).await.is_ok()
+    }
+
+    async fn reject_handler(&mut self, socket: &mut TcpStream, reject: Option<RequestMessage>) -> bool {
+        let reject = match reject {
+            Some(r) => r,
+            None => return false
         };
+        let block = (reject.piece_index as usize, (reject.block_begin / 16384) as usize);
+        if let Some(position) = self.outstanding.iter().position(|b| *b == block) {
+            self.outstanding.remove(position);
+            self.worker.unrequest(block.0, block.1, self.addr);
+            self.request_blocks(socket).await;
+        }
+        true
+    }
+
+    fn cancel_handler(&mut self, cancel: Option<RequestMessage>) -> bool {
+        let cancel = match cancel {
+            Some(c) => c,
+            None => return false
+        };
+        self.requests.retain(|r| r.piece_index != cancel.piece_index || r.block_begin != cancel.block_begin || r.block_length != cancel.block_length);
+        true
+    }
+
+    fn can_serve(&self, request: &RequestMessage) -> bool {
+        request.piece_index >= 0
+            && (request.piece_index as usize) < self.torrent.num_pieces
+            && request.block_begin >= 0
+            && request.block_length > 0
+            && request.block_length <= MAX_REQUEST_LEN
+            //Both come from the peer, an overflow must not wrap past the check
+            && request.block_begin.checked_add(request.block_length).is_some_and(|end| end <= self.torrent.piece_len(request.piece_index))
+            && self.worker.is_complete(request.piece_index as usize)
+    }
 
-        while !self.piece.as_ref().unwrap().is_done(){
-            if self.piece.as_mut().unwrap().request(socket, &self.torrent).await {
-                return
+    async fn serve_requests(&mut self, socket: &mut TcpStream) -> bool {
+        while let Some(request) = self.requests.pop_front() {
+            let block = match self.storage.read_block(&self.torrent, request.piece_index as usize, request.block_begin as usize, request.block_length as usize) {
+                Some(block) => block,
+                None => continue
+            };
+            if socket.write_all(&builders::build_piece(request.piece_index, request.block_begin, block)).await.is_err() {
+                return false
             }
+            self.stats.uploaded.fetch_add(request.block_length as u64, Ordering::Relaxed);
+            self.rates.uploaded.fetch_add(request.block_length as u64, Ordering::Relaxed);
+        }
+        true
+    }
+
+    fn is_waiting_for_blocks(&self) -> bool {
+        matches!(self.status, Status::Leeching) && !self.outstanding.is_empty() && !self.choked
+    }
+
+    async fn piece_handler(&mut self, socket: &mut TcpStream, piece_resp: &PieceMessage) -> bool {
+        self.last_piece = Instant::now();
+        self.rates.downloaded.fetch_add(piece_resp.block.len() as u64, Ordering::Relaxed);
+        self.sample_rate(piece_resp.block.len());
+        if self.worker.is_banned(self.addr) {
+            return false
+        }
+        let piece_index = piece_resp.piece_index as usize;
+        let block_index = (piece_resp.block_begin/16384) as usize;
+        self.outstanding.retain(|b| *b != (piece_index, block_index));
+
+        match self.worker.add_block(piece_index, block_index, piece_resp.block.clone(), self.addr) {
+            BlockResult::Completed(piece_write, contributors) => {
+                let (piece_write, correct) = self.hasher.check(piece_write, &self.torrent).await;
+                if correct {
+                    if self.piece_sender.send(piece_write).await.is_err() {
+                        return false
+                    }
+                } else {
+                    println!("Piece {} failed the hash check, contributed by {:?}", piece_index, contributors);
+                    self.worker.fail(piece_index, &contributors);
+                    if self.worker.is_banned(self.addr) {
+                        return false
+                    }
+                }
+            },
+            BlockResult::Added | BlockResult::Ignored => {}
+        }
+        self.request_blocks(socket).await;
+        true
+    }
+
+    //Another peer delivered a block we also requested in endgame
+    async fn received_elsewhere(&mut self, socket: &mut TcpStream, piece_index: usize, block_index: usize) -> bool {
+        let position = match self.outstanding.iter().position(|b| *b == (piece_index, block_index)) {
+            Some(position) => position,
+            None => return true
+        };
+        self.outstanding.remove(position);
+        if !self.send_cancel(socket, piece_index as i32, block_index).await {
+            return false
+        }
+        self.request_blocks(socket).await;
+        true
+    }
+
+    fn sample_rate(&mut self, bytes: usize) {
+        self.rate_sample_bytes += bytes as u64;
+        let elapsed = self.rate_sample_start.elapsed();
+        if elapsed >= RATE_SAMPLE {
+            self.download_rate = self.rate_sample_bytes as f64 / elapsed.as_secs_f64();
+            self.rate_sample_start = Instant::now();
+            self.rate_sample_bytes = 0;
         }
     }
 }
@@ -236,12 +650,11 @@ fn is_handshake(msg: &[u8]) -> bool {
 }
 
 fn is_available(piece: usize, bitfield: &[bool]) -> bool {
-    bitfield[piece]
+    bitfield.get(piece).copied().unwrap_or(false)
 }
 
-fn is_correct(piece: &PieceWrite, torrent: &Arc<Torrent>) -> bool {
+pub fn is_correct(piece: &PieceWrite, torrent: &Arc<Torrent>) -> bool {
     let mut hasher = Sha1::new();
     hasher.update(&piece.data);
-    let result: Vec<u8> = hasher.finalize().to_vec();
-    result == torrent.hashes[piece.piece_index]
+    torrent.piece_matches(piece.piece_index, &hasher.finalize())
 }
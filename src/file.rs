@@ -1,67 +1,324 @@
-use crate::torrent_parser::Torrent;
-use std::fs::{File, OpenOptions};
-use std::io::{SeekFrom, Seek, Write};
+use crate::{storage::Storage, torrent_parser::Torrent};
+use bencode::Bee;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, SeekFrom, Seek, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+use std::time::UNIX_EPOCH;
 
 #[derive(Debug, Clone)]
 pub struct FileInfo {
     pub offset: i128,
-    pub path: String,
+    pub path: PathBuf,
     pub size: i128,
+    //Was already on disk when we started, so it may hold pieces from an earlier run
+    pub existed: bool,
 }
 
+//The default storage, the torrent's files laid out under a directory
 pub struct TorrentFiles {
-    files: Vec<FileInfo>
+    //output_dir/name, the file itself for single file torrents
+    root: RwLock<PathBuf>,
+    files: RwLock<Vec<FileInfo>>
 }
 
 impl TorrentFiles {
-    pub async fn new(torrent: &Torrent) -> Self {
+    //Single file torrents are saved as output_dir/name, multi-file ones under output_dir/name/
+    pub async fn new(torrent: &Torrent, output_dir: &Path) -> Self {
+        let torrent_files = Self::open(torrent, output_dir);
+
+        for file_info in torrent_files.files() {
+            if let Some(parent) = file_info.path.parent() {
+                fs::create_dir_all(parent).unwrap();
+            }
+            //Existing data is kept so finished pieces can be found again
+            let file = OpenOptions::new().write(true).create(true).truncate(false).open(&file_info.path).unwrap();
+            if file.metadata().unwrap().len() != file_info.size as u64 {
+                file.set_len(file_info.size as u64).unwrap();
+            }
+        }
+        torrent_files
+    }
+
+    //Same layout as new without touching the disk, for reading data that is already there
+    pub fn open(torrent: &Torrent, output_dir: &Path) -> Self {
         let mut files: Vec<FileInfo> = Vec::new();
         let mut constant_size: i128 = 0;
+        let name = torrent.torrent["info"]["name"].get_string().map(|name| sanitize(&name)).unwrap_or_default();
+        let name = if name.is_empty() {"download".to_string()} else {name};
+        let root = output_dir.join(name);
+
         if let Some(files_torrent) = torrent.torrent["info"].get_dict().unwrap().get("files") {
             let files_torrent = files_torrent.get_list().unwrap();
             for file in files_torrent {
                 let size = file["length"].get_int().unwrap();
+                let path = root.join(file_path(&file["path"]));
                 files.push(FileInfo { 
                     offset: constant_size,
-                    path: file["path"][0].get_string().unwrap(),
+                    existed: path.is_file(),
+                    path,
                     size
                 });
                 constant_size += size;
-    
-                let mut file = File::create(file["path"][0].get_string().unwrap()).unwrap();
-                file.seek(SeekFrom::End(size as i64 - 1)).unwrap();
-                file.write_all(&[0]).unwrap();
             }
         } else {
-            let name = torrent.torrent["info"]["name"].get_string().unwrap();
             let size = torrent.torrent["info"]["length"].get_int().unwrap();
             files.push(
-                FileInfo { offset: 0, path: name, size: size }
+                FileInfo { offset: 0, existed: root.is_file(), path: root.clone(), size }
             )
         }
-        
-        
+
         Self {
-            files
+            root: RwLock::new(root),
+            files: RwLock::new(files)
         }
     }
 
-    pub fn write_to_file(&self, torrent: &Torrent, piece_index: usize, data: Vec<u8>) {
+    pub fn files(&self) -> Vec<FileInfo> {
+        self.files.read().unwrap().clone()
+    }
+}
+
+impl Storage for TorrentFiles {
+    fn write_piece(&self, torrent: &Torrent, piece_index: usize, data: &[u8]) -> io::Result<()> {
         let start_bytes = piece_index as i128 * torrent.piece_len as i128;
-        let finish_bytes = start_bytes + torrent.piece_len as i128;
-        
-        for file_info in &self.files {
+        let finish_bytes = start_bytes + data.len() as i128;
+
+        for file_info in self.files.read().unwrap().iter() {
             let start_offset = file_info.offset;
             let end_offset = file_info.size + file_info.offset;
 
-            if start_offset <= finish_bytes && end_offset >= start_bytes {
+            if start_offset < finish_bytes && end_offset > start_bytes {
                 let data_start = (start_offset - start_bytes).max(0) as usize;
                 let data_end = (end_offset - start_bytes).min(data.len() as i128) as usize;
 
-                let mut file = OpenOptions::new().write(true).create(true).open(&file_info.path).unwrap();
-                file.seek(SeekFrom::Start((data_start as i128 + start_bytes - start_offset) as u64)).unwrap();
-                file.write_all(&data[data_start..data_end]).unwrap()
+                let mut file = OpenOptions::new().write(true).open(&file_info.path)?;
+                file.seek(SeekFrom::Start((data_start as i128 + start_bytes - start_offset) as u64))?;
+                file.write_all(&data[data_start..data_end])?;
             }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn file_states(&self) -> Vec<(u64, u128)> {
+        self.files.read().unwrap().iter().map(|file_info| {
+            let metadata = fs::metadata(&file_info.path).ok();
+            let size = metadata.as_ref().map(|metadata| metadata.len()).unwrap_or(0);
+            let mtime = metadata.and_then(|metadata| metadata.modified().ok())
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map(|mtime| mtime.as_nanos())
+                .unwrap_or(0);
+            (size, mtime)
+        }).collect()
+    }
+
+    //Whether any file the piece spans was on disk before this run
+    fn may_have_piece(&self, torrent: &Torrent, piece_index: usize) -> bool {
+        let start_bytes = piece_index as i128 * torrent.piece_len as i128;
+        let finish_bytes = start_bytes + torrent.piece_len(piece_index as i32) as i128;
+        self.files.read().unwrap().iter().any(|file_info| file_info.existed && file_info.offset < finish_bytes && file_info.offset + file_info.size > start_bytes)
+    }
+
+    fn read_block(&self, torrent: &Torrent, piece_index: usize, begin: usize, length: usize) -> Option<Vec<u8>> {
+        let start_bytes = piece_index as i128 * torrent.piece_len as i128 + begin as i128;
+        let finish_bytes = start_bytes + length as i128;
+        let mut data: Vec<u8> = vec![0; length];
+
+        for file_info in self.files.read().unwrap().iter() {
+            let start_offset = file_info.offset;
+            let end_offset = file_info.size + file_info.offset;
+
+            if start_offset < finish_bytes && end_offset > start_bytes {
+                let data_start = (start_offset - start_bytes).max(0) as usize;
+                let data_end = (end_offset - start_bytes).min(length as i128) as usize;
+
+                let mut file = File::open(&file_info.path).ok()?;
+                file.seek(SeekFrom::Start((data_start as i128 + start_bytes - start_offset) as u64)).ok()?;
+                file.read_exact(&mut data[data_start..data_end]).ok()?;
+            }
+        }
+        Some(data)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for file_info in self.files.read().unwrap().iter() {
+            OpenOptions::new().write(true).open(&file_info.path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    //Renames the whole download into dir, which has to be on the same file system
+    fn move_to(&self, dir: &Path) -> io::Result<()> {
+        let mut root = self.root.write().unwrap();
+        let mut files = self.files.write().unwrap();
+        let new_root = dir.join(root.file_name().unwrap_or_default());
+        fs::create_dir_all(dir)?;
+        fs::rename(&*root, &new_root)?;
+
+        for file_info in files.iter_mut() {
+            file_info.path = match file_info.path.strip_prefix(&*root) {
+                Ok(relative) if !relative.as_os_str().is_empty() => new_root.join(relative),
+                _ => new_root.clone()
+            };
+        }
+        *root = new_root;
+        Ok(())
+    }
+
+    //Removes the torrent's files and the directories left empty, anything else in them is kept
+    fn delete(&self) -> io::Result<()> {
+        let root = self.root.read().unwrap();
+        for file_info in self.files.read().unwrap().iter() {
+            match fs::remove_file(&file_info.path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+            let mut parent = file_info.path.parent();
+            while let Some(dir) = parent.filter(|dir| dir.starts_with(&*root)) {
+                if fs::remove_dir(dir).is_err() {
+                    break;
+                }
+                parent = dir.parent();
+            }
+        }
+        Ok(())
+    }
+}
+
+//Joins the sanitized components of a file's path list, dropping empty, "." and ".." ones so nothing escapes the torrent's directory
+fn file_path(path: &Bee) -> PathBuf {
+    let mut result = PathBuf::new();
+    let components = match path.get_list() {
+        Some(components) => components,
+        None => return PathBuf::from("file")
+    };
+    for component in components.iter().filter_map(|component| component.get_string()) {
+        let component = sanitize(&component);
+        if matches!(Path::new(&component).components().next(), Some(Component::Normal(_))) {
+            result.push(component);
+        }
+    }
+    if result.as_os_str().is_empty() {
+        result.push("file");
+    }
+    result
+}
+
+//Replaces characters that are illegal or meaningful in file names on common platforms
+fn sanitize(component: &str) -> String {
+    let mut result: String = component.chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*') {'_'} else {c})
+        .collect();
+    //Windows drops trailing dots and spaces
+    result.truncate(result.trim_end_matches(['.', ' ']).len());
+
+    let stem = result.split('.').next().unwrap_or_default().to_ascii_uppercase();
+    let reserved = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4 && (stem.starts_with("COM") || stem.starts_with("LPT")) && stem.as_bytes()[3].is_ascii_digit());
+    if reserved {
+        result.insert(0, '_');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Component, Path, PathBuf};
+    use std::{env, fs, process};
+    use bencode::BeeValue;
+    use crate::{storage::Storage, torrent_parser::test_torrent};
+    use super::{file_path, sanitize, TorrentFiles};
+
+    fn path(components: &[&str]) -> PathBuf {
+        let list: String = components.iter().map(|component| format!("{}:{}", component.len(), component)).collect();
+        file_path(&BeeValue::from_bytes(format!("l{}e", list).as_bytes()))
+    }
+
+    #[test]
+    fn parent_components_are_dropped() {
+        assert_eq!(path(&["..", "..", "etc", "passwd"]), PathBuf::from("etc/passwd"));
+        assert_eq!(path(&["a", "..", "b"]), PathBuf::from("a/b"));
+        assert_eq!(path(&["...", "a"]), PathBuf::from("a"));
+    }
+
+    #[test]
+    fn rooted_components_stay_relative() {
+        assert_eq!(path(&["/etc", "passwd"]), PathBuf::from("_etc/passwd"));
+        assert_eq!(path(&["C:\\Windows", "system32"]), PathBuf::from("C__Windows/system32"));
+        assert_eq!(path(&["a/../../b"]), PathBuf::from("a_.._.._b"));
+    }
+
+    #[test]
+    fn empty_components_are_dropped() {
+        assert_eq!(path(&["", "a", ".", "b"]), PathBuf::from("a/b"));
+        assert_eq!(path(&["", ".", ".."]), PathBuf::from("file"));
+        assert_eq!(path(&[]), PathBuf::from("file"));
+    }
+
+    #[test]
+    fn reserved_and_illegal_names() {
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize("com1"), "_com1");
+        assert_eq!(sanitize("LPT9.log"), "_LPT9.log");
+        assert_eq!(sanitize("COMX"), "COMX");
+        assert_eq!(sanitize("console"), "console");
+        assert_eq!(sanitize("a<b>c:d\"e|f?g*h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(sanitize("tab\there\0"), "tab_here_");
+        assert_eq!(sanitize("name. . "), "name");
+    }
+
+    #[test]
+    fn files_stay_inside_output_dir() {
+        let files = "d6:lengthi1e4:pathl2:..2:..3:etcee\
+            d6:lengthi1e4:pathl5:/rootee\
+            d6:lengthi1e4:pathl0:1:.ee\
+            d6:lengthi1e4:pathl3:CON6:a\\..\\bee";
+        let torrent = test_torrent("../..", &format!("5:filesl{}e", files), 16384, &[b'a'; 20]);
+        let output_dir = Path::new("/tmp/out");
+
+        for file_info in TorrentFiles::open(&torrent, output_dir).files() {
+            let relative = file_info.path.strip_prefix(output_dir).unwrap();
+            assert!(relative.components().count() >= 2, "{:?}", file_info.path);
+            assert!(relative.components().all(|component| matches!(component, Component::Normal(_))), "{:?}", file_info.path);
+        }
+    }
+
+    #[tokio::test]
+    async fn move_and_delete() {
+        let dir = env::temp_dir().join(format!("storage-test-{}", process::id()));
+        let files = "d6:lengthi3e4:pathl1:aeed6:lengthi2e4:pathl3:sub1:bee";
+        let torrent = test_torrent("multi", &format!("5:filesl{}e", files), 16384, &[b'a'; 20]);
+        let storage = TorrentFiles::new(&torrent, &dir.join("out")).await;
+        storage.write_piece(&torrent, 0, b"abcde").unwrap();
+
+        storage.move_to(&dir.join("moved")).unwrap();
+        assert!(!dir.join("out/multi").exists());
+        assert_eq!(fs::read(dir.join("moved/multi/sub/b")).unwrap(), b"de");
+        assert_eq!(storage.read_block(&torrent, 0, 0, 5).unwrap(), b"abcde");
+
+        //Files that aren't part of the torrent survive, and so does their directory
+        fs::write(dir.join("moved/multi/other"), b"x").unwrap();
+        storage.delete().unwrap();
+        assert!(!dir.join("moved/multi/a").exists());
+        assert!(!dir.join("moved/multi/sub").exists());
+        assert!(dir.join("moved/multi/other").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn move_and_delete_single_file() {
+        let dir = env::temp_dir().join(format!("storage-test-single-{}", process::id()));
+        let torrent = test_torrent("single", "6:lengthi4e", 16384, &[b'a'; 20]);
+        let storage = TorrentFiles::new(&torrent, &dir).await;
+        storage.write_piece(&torrent, 0, b"abcd").unwrap();
+
+        storage.move_to(&dir.join("moved")).unwrap();
+        assert_eq!(fs::read(dir.join("moved/single")).unwrap(), b"abcd");
+
+        storage.delete().unwrap();
+        assert!(!dir.join("moved/single").exists());
+        assert!(dir.join("moved").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use std::{sync::Arc, thread};
+use tokio::{sync::Semaphore, task::{self, JoinSet}};
+use crate::{download::is_correct, piece::PieceWrite, storage::Storage, torrent_parser::Torrent};
+
+//Runs SHA-1 checks on blocking threads so they never stall network I/O, at most one per permit at a time
+#[derive(Clone)]
+pub struct Hasher {
+    permits: Arc<Semaphore>
+}
+
+impl Hasher {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(threads.max(1)))
+        }
+    }
+
+    //One hashing thread per core
+    pub fn per_core() -> Self {
+        Self::new(thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1))
+    }
+
+    async fn run<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> T {
+        let _permit = self.permits.acquire().await.unwrap();
+        task::spawn_blocking(job).await.unwrap()
+    }
+
+    //Hands the piece back along with whether it matched its hash
+    pub async fn check(&self, piece: PieceWrite, torrent: &Arc<Torrent>) -> (PieceWrite, bool) {
+        let torrent = torrent.clone();
+        self.run(move || {
+            let correct = is_correct(&piece, &torrent);
+            (piece, correct)
+        }).await
+    }
+
+    //Checks the stored pieces in parallel, returns the correct ones in order
+    pub async fn check_stored(&self, torrent: &Arc<Torrent>, storage: &Arc<dyn Storage>, pieces: Vec<usize>) -> Vec<usize> {
+        let mut checks = JoinSet::new();
+        for piece_index in pieces {
+            let (hasher, torrent, storage) = (self.clone(), torrent.clone(), storage.clone());
+            checks.spawn(async move {
+                hasher.run(move || {
+                    let hash = storage.hash_piece(&torrent, piece_index)?;
+                    torrent.piece_matches(piece_index, &hash).then_some(piece_index)
+                }).await
+            });
+        }
+
+        let mut correct = Vec::new();
+        while let Some(result) = checks.join_next().await {
+            correct.extend(result.unwrap());
+        }
+        correct.sort();
+        correct
+    }
+}
@@ -0,0 +1,218 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use bencode::{Bee, BeeValue};
+use tokio::{net::TcpStream, io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, time::timeout};
+use tokio_rustls::{TlsConnector, rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName}};
+use url::{Host, Url};
+use crate::{tracker::{parse_compact_peers, Announce, AnnounceRequest, Scrape, Tracker}, peer_id, Address};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_REDIRECTS: usize = 5;
+
+pub async fn get_peers(request: &AnnounceRequest, addr: String) -> Option<Tracker> {
+    let mut url = Url::parse(&addr).ok()?;
+    let mut query = format!(
+        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+        percent_encode(&request.info_hash), percent_encode(peer_id()), request.port, request.uploaded, request.downloaded, request.left
+    );
+    if let Some(event) = request.event.as_str() {
+        query = format!("{}&event={}", query, event);
+    }
+    let query = match url.query() {
+        Some(existing) => format!("{}&{}", existing, query),
+        None => query
+    };
+    url.set_query(Some(&query));
+
+    let body = get(url).await?;
+    let response = BeeValue::from_bytes(&body);
+    let dict = response.get_dict()?;
+
+    if let Some(reason) = dict.get("failure reason") {
+        println!("Tracker {} failed: {}", addr, reason.get_string().unwrap_or_default());
+        return None
+    }
+    if let Some(warning) = dict.get("warning message") {
+        println!("Tracker {} warning: {}", addr, warning.get_string().unwrap_or_default());
+    }
+
+    let get_u32 = |key: &str| dict.get(key).and_then(|v| v.get_int()).and_then(|v| u32::try_from(v).ok()).unwrap_or(0);
+    let mut peers = match dict.get("peers") {
+        Some(peers) => parse_peers(peers),
+        None => Vec::new()
+    };
+    //BEP 7
+    if let Some(peers6) = dict.get("peers6").and_then(|peers6| peers6.get_raw()) {
+        peers.extend(parse_compact_peers(&peers6, true));
+    }
+
+    Some(Tracker {
+        announce: Announce {
+            action: 1,
+            transaction_id: 0,
+            interval: get_u32("interval"),
+            min_interval: get_u32("min interval"),
+            leechers: get_u32("incomplete"),
+            seeders: get_u32("complete"),
+            peers
+        },
+        url: addr
+    })
+}
+
+pub async fn scrape(info_hashes: &[Vec<u8>], addr: String) -> Option<Vec<Scrape>> {
+    let scrape_url = scrape_url(&addr)?;
+    let mut scrapes: Vec<Scrape> = Vec::with_capacity(info_hashes.len());
+
+    //files is keyed by the raw info hash, asking for one hash at a time lets us take its only entry
+    for info_hash in info_hashes {
+        let mut url = scrape_url.clone();
+        let query = match url.query() {
+            Some(existing) => format!("{}&info_hash={}", existing, percent_encode(info_hash)),
+            None => format!("info_hash={}", percent_encode(info_hash))
+        };
+        url.set_query(Some(&query));
+
+        let body = get(url).await?;
+        let response = BeeValue::from_bytes(&body);
+        let dict = response.get_dict()?;
+        if let Some(reason) = dict.get("failure reason") {
+            println!("Tracker {} failed: {}", addr, reason.get_string().unwrap_or_default());
+            return None
+        }
+
+        let files = match dict.get("files").and_then(|files| files.get_dict()) {
+            Some(files) => files,
+            None => continue
+        };
+        let file = match files.values().next().and_then(|file| file.get_dict()) {
+            Some(file) => file,
+            None => continue
+        };
+        let get_u32 = |key: &str| file.get(key).and_then(|v| v.get_int()).and_then(|v| u32::try_from(v).ok()).unwrap_or(0);
+        scrapes.push(Scrape {
+            info_hash: info_hash.clone(),
+            seeders: get_u32("complete"),
+            completed: get_u32("downloaded"),
+            leechers: get_u32("incomplete"),
+        });
+    }
+    Some(scrapes)
+}
+
+//The scrape URL replaces the last "announce" path segment with "scrape" (BEP 48)
+fn scrape_url(addr: &str) -> Option<Url> {
+    let mut url = Url::parse(addr).ok()?;
+    let path = url.path().to_string();
+    let (dir, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    url.set_path(&format!("{}/scrape{}", dir, rest));
+    Some(url)
+}
+
+fn parse_peers(peers: &Bee) -> Vec<Address> {
+    //Compact (BEP 23)
+    if let Some(compact) = peers.get_raw() {
+        return parse_compact_peers(&compact, false)
+    }
+
+    let mut result: Vec<Address> = Vec::new();
+    let peers = match peers.get_list() {
+        Some(peers) => peers,
+        None => return result
+    };
+    for peer in peers.iter() {
+        let peer = match peer.get_dict() {
+            Some(peer) => peer,
+            None => continue
+        };
+        let ip = peer.get("ip").and_then(|ip| ip.get_string()).and_then(|ip| ip.parse::<IpAddr>().ok());
+        let port = peer.get("port").and_then(|port| port.get_int()).and_then(|port| u16::try_from(port).ok());
+        if let (Some(ip), Some(port)) = (ip, port) {
+            result.push(Address::new(ip, port));
+        }
+    }
+    result
+}
+
+pub async fn get(mut url: Url) -> Option<Vec<u8>> {
+    for _ in 0..MAX_REDIRECTS {
+        let response = timeout(REQUEST_TIMEOUT, request(&url)).await.ok()??;
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&response[..header_end]).to_string();
+        let mut lines = headers.lines();
+        let status: u16 = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+
+        match status {
+            200 => return Some(response[header_end + 4..].to_vec()),
+            301 | 302 | 303 | 307 | 308 => {
+                let location = lines
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))?
+                    .1.trim().to_string();
+                url = url.join(&location).ok()?;
+            },
+            _ => return None
+        }
+    }
+    None
+}
+
+async fn request(url: &Url) -> Option<Vec<u8>> {
+    let host = url.host_str()?;
+    let port = url.port_or_known_default()?;
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string()
+    };
+    let req = format!("GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: AT0001\r\nConnection: close\r\n\r\n", path, host);
+
+    //host_str keeps the brackets around IPv6 literals, which neither connect nor rustls accept
+    let (stream, server_name) = match url.host()? {
+        Host::Domain(domain) => (TcpStream::connect((domain, port)).await.ok()?, ServerName::try_from(domain).ok()),
+        Host::Ipv4(ip) => (TcpStream::connect((ip, port)).await.ok()?, Some(ServerName::IpAddress(ip.into()))),
+        Host::Ipv6(ip) => (TcpStream::connect((ip, port)).await.ok()?, Some(ServerName::IpAddress(ip.into())))
+    };
+    match url.scheme() {
+        "http" => send(stream, &req).await,
+        "https" => {
+            let server_name = server_name?;
+            let stream = tls_connector().connect(server_name, stream).await.ok()?;
+            send(stream, &req).await
+        },
+        _ => None
+    }
+}
+
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, req: &str) -> Option<Vec<u8>> {
+    stream.write_all(req.as_bytes()).await.ok()?;
+    let mut response: Vec<u8> = Vec::new();
+    //Some servers close TLS without close_notify, keep whatever was read
+    let _ = stream.read_to_end(&mut response).await;
+    if response.is_empty() {
+        return None
+    }
+    Some(response)
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len() * 3);
+    for byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => result.push(*byte as char),
+            _ => result.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    result
+}
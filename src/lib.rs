@@ -0,0 +1,35 @@
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use rand::Rng;
+pub mod tracker;
+pub mod torrent_parser;
+pub mod download;
+pub mod file;
+mod message;
+mod queue;
+mod piece;
+pub mod peers;
+pub mod magnet;
+pub mod metadata;
+mod http_tracker;
+pub mod tracker_manager;
+pub mod listener;
+pub mod choker;
+mod resume;
+mod hasher;
+pub mod storage;
+pub mod verify;
+
+pub type Address = SocketAddr;
+
+static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+
+//Generated once so trackers and peers see the same id for the whole session
+pub fn peer_id() -> &'static [u8; 20] {
+    PEER_ID.get_or_init(|| {
+        let mut id = [0; 20];
+        id[0..8].copy_from_slice(b"-AT0001-");
+        rand::thread_rng().fill(&mut id[8..]);
+        id
+    })
+}
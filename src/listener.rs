@@ -0,0 +1,89 @@
+use std::{collections::HashMap, net::{Ipv4Addr, Ipv6Addr}, sync::{Arc, Mutex}, time::Duration};
+use tokio::{net::{TcpListener, TcpStream}, io::AsyncReadExt, sync::mpsc::{channel, Receiver, Sender}, time::{sleep, timeout}};
+use crate::{peer_id, Address};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+//Ports tried after the requested one before falling back to any free port
+const PORT_ATTEMPTS: u16 = 10;
+//Accept errors like running out of file descriptors persist for a while, retrying at once would spin
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+pub struct Incoming {
+    pub socket: TcpStream,
+    pub addr: Address,
+    pub handshake: Vec<u8>
+}
+
+pub struct Listener {
+    pub port: u16,
+    torrents: Arc<Mutex<HashMap<Vec<u8>, Sender<Incoming>>>>
+}
+
+impl Listener {
+    pub async fn bind(port: u16) -> Listener {
+        let ports = (0..PORT_ATTEMPTS).map(|i| port.saturating_add(i)).chain([0]);
+        let mut listener = None;
+        for port in ports {
+            //[::] accepts IPv4 connections too on dual-stack hosts
+            if let Ok(l) = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+                listener = Some(l);
+                break;
+            }
+            if let Ok(l) = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+                listener = Some(l);
+                break;
+            }
+        }
+        let listener = listener.expect("Could not bind a listening port");
+        let port = listener.local_addr().unwrap().port();
+        println!("Listening on port {}", port);
+
+        let torrents: Arc<Mutex<HashMap<Vec<u8>, Sender<Incoming>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let accept_torrents = torrents.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, addr) = match listener.accept().await {
+                    Ok(a) => a,
+                    Err(_) => {
+                        sleep(ACCEPT_RETRY).await;
+                        continue
+                    }
+                };
+                let torrents = accept_torrents.clone();
+                tokio::spawn(async move {
+                    let addr = Address::new(addr.ip().to_canonical(), addr.port());
+                    accept(socket, addr, torrents).await
+                });
+            }
+        });
+
+        Listener {
+            port,
+            torrents
+        }
+    }
+
+    pub fn register(&self, info_hash: Vec<u8>) -> Receiver<Incoming> {
+        let (sender, receiver) = channel::<Incoming>(10);
+        self.torrents.lock().unwrap().insert(info_hash, sender);
+        receiver
+    }
+}
+
+async fn accept(mut socket: TcpStream, addr: Address, torrents: Arc<Mutex<HashMap<Vec<u8>, Sender<Incoming>>>>) {
+    let mut handshake = vec![0; 68];
+    match timeout(HANDSHAKE_TIMEOUT, socket.read_exact(&mut handshake)).await {
+        Ok(Ok(_)) => {},
+        _ => return
+    }
+
+    if handshake[0] != 19 || &handshake[1..20] != b"BitTorrent protocol" || &handshake[48..68] == peer_id() {
+        return
+    }
+
+    let sender = match torrents.lock().unwrap().get(&handshake[28..48]) {
+        Some(sender) => sender.clone(),
+        None => return
+    };
+    let _ = sender.send(Incoming { socket, addr, handshake }).await;
+}
@@ -0,0 +1,137 @@
+use url::Url;
+use crate::Address;
+
+pub struct Magnet {
+    pub info_hash: Vec<u8>,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<Address>
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Option<Magnet> {
+        let url = Url::parse(uri).ok()?;
+        if url.scheme() != "magnet" {
+            return None
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers: Vec<String> = Vec::new();
+        let mut peers: Vec<Address> = Vec::new();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" if value.starts_with("urn:btih:") => {
+                    info_hash = decode_info_hash(&value["urn:btih:".len()..]);
+                },
+                "dn" => name = Some(value.into_owned()),
+                "tr" if !trackers.contains(&value.to_string()) => {
+                    trackers.push(value.into_owned());
+                },
+                "x.pe" => {
+                    if let Ok(peer) = value.parse::<Address>() {
+                        peers.push(peer);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        Some(Magnet {
+            info_hash: info_hash?,
+            name,
+            trackers,
+            peers
+        })
+    }
+}
+
+fn decode_info_hash(hash: &str) -> Option<Vec<u8>> {
+    match hash.len() {
+        40 => decode_hex(hash),
+        32 => decode_base32(hash),
+        _ => None
+    }
+}
+
+fn decode_hex(hash: &str) -> Option<Vec<u8>> {
+    //from_str_radix would also take a sign
+    if !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None
+    }
+    (0..hash.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hash.get(i..i+2)?, 16).ok())
+        .collect()
+}
+
+fn decode_base32(hash: &str) -> Option<Vec<u8>> {
+    let mut result: Vec<u8> = Vec::with_capacity(20);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in hash.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_base32, decode_hex, Magnet};
+
+    const HEX: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+    const BASE32: &str = "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+
+    fn info_hash() -> Vec<u8> {
+        vec![0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9, 0xf5, 0x19, 0xb3, 0x35, 0xaa, 0x7c, 0x13, 0x67, 0xa8, 0x8a]
+    }
+
+    #[test]
+    fn hex_info_hash() {
+        let magnet = Magnet::parse(&format!("magnet:?xt=urn:btih:{}&dn=name", HEX)).unwrap();
+        assert_eq!(magnet.info_hash, info_hash());
+        assert_eq!(magnet.name.as_deref(), Some("name"));
+        assert_eq!(Magnet::parse(&format!("magnet:?xt=urn:btih:{}", HEX.to_uppercase())).unwrap().info_hash, info_hash());
+    }
+
+    #[test]
+    fn base32_info_hash() {
+        assert_eq!(Magnet::parse(&format!("magnet:?xt=urn:btih:{}", BASE32)).unwrap().info_hash, info_hash());
+        assert_eq!(decode_base32(&BASE32.to_lowercase()).unwrap(), info_hash());
+    }
+
+    #[test]
+    fn invalid_info_hash() {
+        assert!(decode_hex("zz").is_none());
+        assert!(decode_hex("+f").is_none());
+        assert!(decode_hex("é0").is_none());
+        assert!(decode_base32("18").is_none());
+        assert!(Magnet::parse(&format!("magnet:?xt=urn:btih:{}", &HEX[..39])).is_none());
+        assert!(Magnet::parse(&format!("magnet:?xt=urn:btih:{}", BASE32.replace('Y', "1"))).is_none());
+        assert!(Magnet::parse(&format!("http://example.com/?xt=urn:btih:{}", HEX)).is_none());
+        assert!(Magnet::parse("magnet:?dn=name").is_none());
+    }
+
+    #[test]
+    fn duplicate_trackers() {
+        let uri = format!("magnet:?xt=urn:btih:{}&tr=udp%3A%2F%2Fa%3A1&tr=http%3A%2F%2Fb%2Fannounce&tr=udp%3A%2F%2Fa%3A1", HEX);
+        assert_eq!(Magnet::parse(&uri).unwrap().trackers, vec!["udp://a:1", "http://b/announce"]);
+    }
+
+    #[test]
+    fn peers() {
+        let uri = format!("magnet:?xt=urn:btih:{}&x.pe=10.0.0.1%3A6881&x.pe=%5B2001%3Adb8%3A%3A1%5D%3A51413&x.pe=nonsense", HEX);
+        let peers: Vec<String> = Magnet::parse(&uri).unwrap().peers.iter().map(|peer| peer.to_string()).collect();
+        assert_eq!(peers, vec!["10.0.0.1:6881", "[2001:db8::1]:51413"]);
+    }
+}
@@ -1,22 +1,48 @@
 use std::fs;
 use std::env;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Arc;
-mod tracker;
-mod torrent_parser;
-mod download;
-mod file;
-mod message;
-mod queue;
-mod piece;
-mod peers;
 use bencode::Bee;
+use tokio::time::timeout;
 use bencode::BeeValue;
-use peers::Download;
-use torrent_parser::Torrent;
+use bittorrent::{metadata, tracker, verify};
+use bittorrent::choker::TitForTat;
+use bittorrent::listener::Listener;
+use bittorrent::magnet::Magnet;
+use bittorrent::peers::Download;
+use bittorrent::torrent_parser::Torrent;
+use bittorrent::tracker_manager::TrackerManager;
 
-pub type Address = ([u8; 4], u16);
+//Flags following the torrent file or magnet link
+struct Options {
+    suppress_haves: bool,
+    request_queue: Option<usize>,
+    output_dir: PathBuf
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Options {
+        let mut options = Options {
+            suppress_haves: false,
+            request_queue: None,
+            output_dir: PathBuf::from(".")
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--suppress-haves" => options.suppress_haves = true,
+                "--request-queue" => options.request_queue = Some(args.next().and_then(|depth| depth.parse().ok()).expect("--request-queue needs a number")),
+                "--output-dir" => options.output_dir = PathBuf::from(args.next().expect("--output-dir needs a directory")),
+                _ => panic!("Unknown option: {}", arg),
+            }
+        }
+        options
+    }
+}
 
-fn read_torrent(path: &String) -> Bee {
+fn read_torrent(path: &str) -> Bee {
     let torrent_result = fs::read(path);
     let torrent = match torrent_result {
         Ok(file) => file,
@@ -27,12 +53,70 @@ fn read_torrent(path: &String) -> Bee {
 }
 
 
+async fn download(path: &str, options: Options) {
+    let listener = Listener::bind(6881).await;
+    let (torrent, peers) = if path.starts_with("magnet:") {
+        let magnet = match Magnet::parse(path) {
+            Some(magnet) => magnet,
+            None => panic!("Invalid magnet link: {}", path),
+        };
+        (metadata::torrent_from_magnet(&magnet, listener.port).await, magnet.peers)
+    } else {
+        (Torrent::new(&read_torrent(path)), Vec::new())
+    };
+    let torrent = Arc::new(torrent);
+    let download = Download::new(&torrent, peers, Box::new(TitForTat::new(4, 3)), &options.output_dir).await
+        .with_have_suppression(options.suppress_haves)
+        .with_request_queue(options.request_queue);
+
+    download.connect(&listener).await;
+}
+
+async fn scrape(paths: &[String]) {
+    let mut trackers: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+    for path in paths {
+        let (info_hash, urls) = if path.starts_with("magnet:") {
+            let magnet = match Magnet::parse(path) {
+                Some(magnet) => magnet,
+                None => panic!("Invalid magnet link: {}", path),
+            };
+            (magnet.info_hash, magnet.trackers)
+        } else {
+            let torrent = Torrent::new(&read_torrent(path));
+            let urls = TrackerManager::new(&torrent).tiers.into_iter().flat_map(|tier| tier.trackers).collect();
+            (torrent.info_hash(), urls)
+        };
+        for url in urls {
+            trackers.entry(url).or_default().push(info_hash.clone());
+        }
+    }
+
+    for (addr, info_hashes) in trackers {
+        let requests = info_hashes.len().div_ceil(tracker::MAX_SCRAPE_HASHES) as u32;
+        match timeout(tracker::TRACKER_TIMEOUT * requests, tracker::scrape(&info_hashes, addr.clone())).await {
+            Ok(Some(scrapes)) => {
+                for scrape in scrapes {
+                    let info_hash: String = scrape.info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+                    println!("{} {}: {} seeders, {} leechers, {} completed", info_hash, addr, scrape.seeders, scrape.leechers, scrape.completed);
+                }
+            },
+            _ => println!("{}: scrape failed", addr),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = env::args().collect();
-    let path = &args[1];
-    let torrent = Arc::new(Torrent::new(&read_torrent(path)));
-    let download = Download::new(&torrent).await;
-
-    download.connect().await;
-}
\ No newline at end of file
+    match args.get(1).map(|a| a.as_str()) {
+        Some("scrape") if args.len() > 2 => scrape(&args[2..]).await,
+        Some("verify") if args.len() > 3 => {
+            let torrent = Arc::new(Torrent::new(&read_torrent(&args[2])));
+            if !verify::verify(&torrent, Path::new(&args[3])).await {
+                process::exit(1);
+            }
+        },
+        Some(path) => download(path, Options::parse(&args[2..])).await,
+        None => println!("Usage: {} <torrent file or magnet link> [--output-dir <dir>] [--suppress-haves] [--request-queue <depth>]\n       {} scrape <torrent file or magnet link>...\n       {} verify <torrent file> <data dir>", args[0], args[0], args[0]),
+    }
+}
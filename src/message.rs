@@ -1,21 +1,18 @@
 pub mod builders {
     use bytes::{BytesMut, BufMut};
-    use rand::{rngs::ThreadRng, Rng};
-    use crate::torrent_parser::Torrent;
+    use crate::peer_id;
 
-    pub fn build_handshake(torrent: &Torrent) -> BytesMut {
+    pub fn build_handshake(info_hash: &[u8]) -> BytesMut {
         let mut buf = BytesMut::with_capacity(68);
-        let mut rng: ThreadRng = rand::thread_rng();
         buf.put_u8(19);
         buf.put_slice(b"BitTorrent protocol");
         buf.put_u32(0);
-        buf.put_u32(0);
+        //reserved: extension protocol (BEP 10) and fast extension (BEP 6)
+        buf.put_u32(0x00100004);
         
-        buf.put_slice(&Torrent::info_hash(torrent));
+        buf.put_slice(info_hash);
         
-        buf.put_slice(b"-AT0001-");
-        buf.put_u64(rng.gen::<u64>());
-        buf.put_u32(rng.gen::<u32>());
+        buf.put_slice(peer_id());
         buf
     }
     
@@ -124,6 +121,46 @@ pub mod builders {
         buf.put_u16(port);
         buf
     }
+
+    pub fn build_have_all() -> BytesMut {
+        let mut buf = BytesMut::with_capacity(5);
+        buf.put_u32(1);
+        //id=14 have all message
+        buf.put_u8(14);
+        buf
+    }
+
+    pub fn build_have_none() -> BytesMut {
+        let mut buf = BytesMut::with_capacity(5);
+        buf.put_u32(1);
+        //id=15 have none message
+        buf.put_u8(15);
+        buf
+    }
+
+    pub fn build_reject(piece_index: i32, block_index: i32, block_length: i32) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(17);
+        //length
+        buf.put_u32(13);
+        //id=16 reject request message
+        buf.put_u8(16);
+        buf.put_i32(piece_index);
+        buf.put_i32(block_index);
+        buf.put_i32(block_length);
+        buf
+    }
+
+    pub fn build_extended(extended_id: u8, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(payload.len()+6);
+        //length
+        buf.put_u32((payload.len()+2).try_into().unwrap());
+        //id=20 extended message
+        buf.put_u8(20);
+        //extended message id, 0 is the extended handshake
+        buf.put_u8(extended_id);
+        buf.put_slice(payload);
+        buf
+    }
 }
 
 
@@ -142,12 +179,25 @@ pub struct BitfieldMessage {
     pub bitfield: Vec<bool>
 }
 
+pub struct RequestMessage {
+    pub piece_index: i32,
+    pub block_begin: i32,
+    pub block_length: i32
+}
+
+pub struct ExtendedMessage {
+    pub id: u8,
+    pub payload: Vec<u8>
+}
+
 pub struct Message {
     pub size: i32,
     pub id: i8,
     pub have_message: Option<HaveMessage>,
     pub bitfield_message: Option<BitfieldMessage>,
-    pub piece_message: Option<PieceMessage>
+    pub piece_message: Option<PieceMessage>,
+    pub request_message: Option<RequestMessage>,
+    pub extended_message: Option<ExtendedMessage>
 }
 
 pub fn parse(msg: &[u8]) -> Option<Message> {
@@ -163,6 +213,8 @@ pub fn parse(msg: &[u8]) -> Option<Message> {
         have_message: None,
         bitfield_message: None,
         piece_message: None,
+        request_message: None,
+        extended_message: None,
     };
 
     if id == 4 {
@@ -180,6 +232,13 @@ pub fn parse(msg: &[u8]) -> Option<Message> {
             }
         }
         message.bitfield_message = Some(BitfieldMessage { bitfield });
+    } else if (id == 6 || id == 8 || id == 16) && payload.len() >= 12 {
+        //Request, Cancel and Reject Request Messages
+        message.request_message = Some(RequestMessage {
+            piece_index: i32::from_be_bytes(payload[0..4].try_into().unwrap()),
+            block_begin: i32::from_be_bytes(payload[4..8].try_into().unwrap()),
+            block_length: i32::from_be_bytes(payload[8..12].try_into().unwrap())
+        })
     } else if id == 7 {
         //Piece Message
         message.piece_message = Some(PieceMessage {
@@ -187,6 +246,12 @@ pub fn parse(msg: &[u8]) -> Option<Message> {
             block_begin: i32::from_be_bytes(payload[4..8].try_into().unwrap()),
             block: payload[8..].to_vec()
         })
+    } else if id == 20 && !payload.is_empty() {
+        //Extended Message
+        message.extended_message = Some(ExtendedMessage {
+            id: payload[0],
+            payload: payload[1..].to_vec()
+        })
     }
 
     Some(message)
@@ -0,0 +1,214 @@
+use std::{collections::HashSet, time::Duration};
+use bencode::{Bee, BeeValue};
+use sha1::{Sha1, Digest};
+use tokio::{net::TcpStream, io::{AsyncReadExt, AsyncWriteExt}, task::JoinSet, time::{error::Elapsed, timeout}};
+use crate::{magnet::Magnet, message::{builders, parse}, torrent_parser::Torrent, tracker::{get_peers, AnnounceRequest, Event, TRACKER_TIMEOUT}, Address};
+
+//Our local id for ut_metadata, sent in the extended handshake
+const UT_METADATA_ID: u8 = 1;
+const METADATA_PIECE_LEN: usize = 16384;
+const MAX_METADATA_SIZE: usize = 64 * 1024 * 1024;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+//Fetches from the magnet's own peers right away, tracker peers join in as the announces come back
+pub async fn torrent_from_magnet(magnet: &Magnet, port: u16) -> Torrent {
+    let name = magnet.name.clone().unwrap_or_else(|| "magnet".to_string());
+    loop {
+        let mut trackers = JoinSet::new();
+        for addr in &magnet.trackers {
+            let request = AnnounceRequest {
+                info_hash: magnet.info_hash.clone(),
+                port,
+                uploaded: 0,
+                downloaded: 0,
+                //The size is unknown until we have the metadata, any non zero left will do
+                left: 16384,
+                event: Event::None
+            };
+            let addr = addr.clone();
+            trackers.spawn(async move { timeout(TRACKER_TIMEOUT, get_peers(&request, addr)).await });
+        }
+
+        let mut fetches = JoinSet::new();
+        let mut seen: HashSet<Address> = HashSet::new();
+        println!("Fetching metadata for {} from {} peers", name, magnet.peers.len());
+        fetch(&mut fetches, &mut seen, &magnet.info_hash, magnet.peers.clone());
+        loop {
+            tokio::select! {
+                Some(tracker) = trackers.join_next() => {
+                    if let Ok(Ok(Some(tracker))) = tracker {
+                        fetch(&mut fetches, &mut seen, &magnet.info_hash, tracker.announce.peers);
+                    }
+                },
+                Some(result) = fetches.join_next() => {
+                    if let Ok(Ok(Some(info))) = result {
+                        println!("Fetched metadata for {}", name);
+                        return Torrent::from_info(&info, &magnet.trackers);
+                    }
+                },
+                else => break
+            }
+        }
+
+        println!("Could not fetch metadata from {} peers, retrying in {} seconds", seen.len(), RETRY_INTERVAL.as_secs());
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+//Starts fetching from the peers not tried yet
+fn fetch(tasks: &mut JoinSet<Result<Option<Vec<u8>>, Elapsed>>, seen: &mut HashSet<Address>, info_hash: &[u8], peers: Vec<Address>) {
+    for peer in peers.into_iter().filter(|peer| seen.insert(*peer)) {
+        let info_hash = info_hash.to_vec();
+        tasks.spawn(async move {
+            timeout(PEER_TIMEOUT, fetch_from_peer(peer, info_hash)).await
+        });
+    }
+}
+
+async fn fetch_from_peer(peer: Address, info_hash: Vec<u8>) -> Option<Vec<u8>> {
+    let mut socket = TcpStream::connect(peer).await.ok()?;
+    socket.write_all(&builders::build_handshake(&info_hash)).await.ok()?;
+
+    let mut handshake = [0; 68];
+    socket.read_exact(&mut handshake).await.ok()?;
+    if handshake[28..48] != info_hash[..] || handshake[25] & 0x10 == 0 {
+        return None
+    }
+
+    let ext_handshake = format!("d1:md11:ut_metadatai{}eee", UT_METADATA_ID);
+    socket.write_all(&builders::build_extended(0, ext_handshake.as_bytes())).await.ok()?;
+
+    let mut metadata: Vec<u8> = Vec::new();
+    let mut received: Vec<bool> = Vec::new();
+    loop {
+        let msg = read_message(&mut socket).await?;
+        let extended = match parse(&msg) {
+            Some(m) if m.id == 20 => m.extended_message?,
+            Some(_) => continue,
+            None => continue
+        };
+
+        if extended.id == 0 {
+            let handshake = BeeValue::from_bytes(&extended.payload);
+            let their_id = get_int(&get_dict_value(&handshake, "m")?, "ut_metadata")?;
+            let metadata_size = get_int(&handshake, "metadata_size")? as usize;
+            if their_id == 0 || metadata_size == 0 || metadata_size > MAX_METADATA_SIZE {
+                return None
+            }
+
+            metadata = vec![0; metadata_size];
+            received = vec![false; metadata_size.div_ceil(METADATA_PIECE_LEN)];
+            for piece in 0..received.len() {
+                let request = format!("d8:msg_typei0e5:piecei{}ee", piece);
+                socket.write_all(&builders::build_extended(their_id as u8, request.as_bytes())).await.ok()?;
+            }
+        } else if extended.id == UT_METADATA_ID && !received.is_empty() {
+            let header_len = bencode_len(&extended.payload)?;
+            let header = BeeValue::from_bytes(&extended.payload[..header_len]);
+            let piece = get_int(&header, "piece")? as usize;
+            match get_int(&header, "msg_type")? {
+                //data
+                1 => {
+                    let data = &extended.payload[header_len..];
+                    let start = piece * METADATA_PIECE_LEN;
+                    if piece >= received.len() || start + data.len() > metadata.len() {
+                        return None
+                    }
+                    metadata[start..start + data.len()].copy_from_slice(data);
+                    received[piece] = true;
+
+                    if received.iter().all(|r| *r) {
+                        let mut hasher = Sha1::new();
+                        hasher.update(&metadata);
+                        if hasher.finalize().to_vec() == info_hash {
+                            return Some(metadata)
+                        }
+                        return None
+                    }
+                },
+                //reject
+                2 => return None,
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn read_message(socket: &mut TcpStream) -> Option<Vec<u8>> {
+    loop {
+        let mut len = [0; 4];
+        socket.read_exact(&mut len).await.ok()?;
+        let size = u32::from_be_bytes(len) as usize;
+        if size == 0 {
+            //keep-alive
+            continue;
+        }
+        if size > MAX_METADATA_SIZE {
+            return None
+        }
+
+        let mut msg = vec![0; size + 4];
+        msg[0..4].copy_from_slice(&len);
+        socket.read_exact(&mut msg[4..]).await.ok()?;
+        return Some(msg)
+    }
+}
+
+fn get_dict_value(bee: &Bee, key: &str) -> Option<Bee> {
+    bee.get_dict()?.get(key).cloned()
+}
+
+fn get_int(bee: &Bee, key: &str) -> Option<i128> {
+    get_dict_value(bee, key)?.get_int()
+}
+
+//Length of the bencoded value at the start of buf, ut_metadata data messages append the raw piece after the dictionary
+fn bencode_len(buf: &[u8]) -> Option<usize> {
+    match buf.first()? {
+        b'i' => Some(buf.iter().position(|b| *b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = 1;
+            while *buf.get(pos)? != b'e' {
+                pos += bencode_len(&buf[pos..])?;
+            }
+            Some(pos + 1)
+        },
+        b'0'..=b'9' => {
+            let colon = buf.iter().position(|b| *b == b':')?;
+            let len: usize = std::str::from_utf8(&buf[..colon]).ok()?.parse().ok()?;
+            Some(colon + 1 + len).filter(|end| *end <= buf.len())
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bencode_len;
+
+    #[test]
+    fn data_message_with_piece() {
+        let header = b"d8:msg_typei1e5:piecei0e10:total_sizei5ee";
+        let mut payload = header.to_vec();
+        payload.extend_from_slice(b"d1:ae");
+        assert_eq!(bencode_len(&payload), Some(header.len()));
+    }
+
+    #[test]
+    fn nested_values() {
+        assert_eq!(bencode_len(b"i-42e"), Some(5));
+        assert_eq!(bencode_len(b"4:spamtrailing"), Some(6));
+        assert_eq!(bencode_len(b"l4:spamli1eeed1:xi2ee"), Some(13));
+    }
+
+    #[test]
+    fn truncated_or_invalid() {
+        assert_eq!(bencode_len(b""), None);
+        assert_eq!(bencode_len(b"d8:msg_typei1e"), None);
+        assert_eq!(bencode_len(b"10:short"), None);
+        assert_eq!(bencode_len(b"x"), None);
+        assert_eq!(bencode_len(b"i12"), None);
+        assert_eq!(bencode_len(b"-1:a"), None);
+    }
+}
@@ -1,76 +1,308 @@
-use std::{sync::{Arc, Mutex}, collections::HashSet};
-use tokio::sync::{mpsc::channel, broadcast};
+use std::{path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, collections::{HashMap, HashSet}, time::Duration};
+use tokio::{sync::{mpsc::{channel, Sender}, broadcast}, time::{interval, timeout}};
 
-use crate::{queue::PieceQueue, tracker::get_peers, torrent_parser::Torrent, file::TorrentFiles, download::{Peer, Status}, piece::PieceWrite, Address};
+use crate::{listener::Listener, queue::PieceQueue, tracker_manager::TrackerManager, tracker::{AnnounceRequest, Event}, torrent_parser::Torrent, file::TorrentFiles, storage::Storage, download::{Peer, PeerCommand, PeerHandle, Status}, choker::Choker, hasher::Hasher, piece::PieceWrite, resume::{self, ResumeData}, Address};
 
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(10);
+const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct TransferStats {
+    pub uploaded: AtomicU64,
+    pub downloaded: AtomicU64,
+    pub left: AtomicU64
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left)
+        }
+    }
+
+    pub fn announce_request(&self, info_hash: &[u8], port: u16, event: Event) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: info_hash.to_vec(),
+            port,
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.left.load(Ordering::Relaxed),
+            event
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TorrentContext {
+    pub work_queue: Arc<PieceQueue>,
+    pub storage: Arc<dyn Storage>,
+    pub stats: Arc<TransferStats>,
+    pub torrent: Arc<Torrent>,
+    pub hasher: Hasher,
+    pub suppress_haves: bool,
+    pub request_queue: Option<usize>
+}
 
 pub struct Download {
     work_queue: Arc<PieceQueue>,
     peers: Arc<Mutex<HashSet<Address>>>,
-    files: TorrentFiles,
-    torrent: Arc<Torrent>
+    storage: Arc<dyn Storage>,
+    torrent: Arc<Torrent>,
+    stats: Arc<TransferStats>,
+    handles: Mutex<HashMap<Address, PeerHandle>>,
+    choker: Mutex<Box<dyn Choker>>,
+    hasher: Hasher,
+    suppress_haves: bool,
+    request_queue: Option<usize>,
+    resume_path: PathBuf,
+    initial_peers: Vec<Address>
 }
 
 impl Download {
-    pub async fn new(torrent: &Arc<Torrent>) -> Self {
+    //Saves the torrent's files under output_dir
+    pub async fn new(torrent: &Arc<Torrent>, initial_peers: Vec<Address>, choker: Box<dyn Choker>, output_dir: &Path) -> Self {
+        let storage = Arc::new(TorrentFiles::new(torrent, output_dir).await);
+        Self::new_with_storage(torrent, initial_peers, choker, storage, output_dir).await
+    }
+
+    //Keeps the data in any storage backend, the resume file is still written to resume_dir
+    pub async fn new_with_storage(torrent: &Arc<Torrent>, mut initial_peers: Vec<Address>, choker: Box<dyn Choker>, storage: Arc<dyn Storage>, resume_dir: &Path) -> Self {
+        let work_queue = Arc::new(PieceQueue::new());
+        let hasher = Hasher::per_core();
+        let size: u64 = torrent.size.try_into().unwrap();
+        let stats = TransferStats::new(size);
+
+        let info_hash = torrent.info_hash();
+        let hex: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        let resume_path = resume_dir.join(format!("{}.resume", hex));
+        let resume = ResumeData::load(&resume_path, &info_hash);
+
+        let verified = match resume {
+            Some(resume) => {
+                stats.uploaded.store(resume.uploaded, Ordering::Relaxed);
+                stats.downloaded.store(resume.downloaded, Ordering::Relaxed);
+                for peer in &resume.peers {
+                    if !initial_peers.contains(peer) {
+                        initial_peers.push(*peer);
+                    }
+                }
+                //Trusted only if nothing touched the files since it was written
+                if resume.files == storage.file_states() {
+                    restore(torrent, &resume, &work_queue)
+                } else {
+                    recheck(torrent, &storage, &work_queue, &hasher).await
+                }
+            },
+            None => recheck(torrent, &storage, &work_queue, &hasher).await
+        };
+        stats.left.store(size - verified, Ordering::Relaxed);
+
         Self {
-            work_queue: Arc::new(PieceQueue::new()),
+            work_queue,
             peers: Arc::new(Mutex::new(HashSet::new())),
-            files: TorrentFiles::new(torrent).await,
-            torrent: torrent.clone()
+            storage,
+            torrent: torrent.clone(),
+            stats: Arc::new(stats),
+            handles: Mutex::new(HashMap::new()),
+            choker: Mutex::new(choker),
+            hasher,
+            suppress_haves: false,
+            request_queue: None,
+            resume_path,
+            initial_peers
         }
     }
 
-    pub async fn connect(&self) {
+    //Skip sending have for pieces a peer already announced
+    pub fn with_have_suppression(mut self, suppress_haves: bool) -> Self {
+        self.suppress_haves = suppress_haves;
+        self
+    }
+
+    //Fixed number of outstanding requests per peer instead of sizing it from the download rate
+    pub fn with_request_queue(mut self, request_queue: Option<usize>) -> Self {
+        self.request_queue = request_queue;
+        self
+    }
+
+    //Downloads until every piece is verified, then keeps seeding until interrupted
+    pub async fn connect(&self, listener: &Listener) {
+        let mut incoming = listener.register(self.torrent.info_hash());
         let (result_sender, mut result_receiver) = channel::<PieceWrite>(self.torrent.num_pieces);
+        let (peer_sender, mut peer_receiver) = channel::<Vec<Address>>(10);
         let (tx, rx) = broadcast::channel::<Status>(10);
 
-        for tracker in &self.torrent.torrent["announce-list"].get_list().unwrap() {
-            let addr = tracker[0].get_string().unwrap();
-            let torrent = self.torrent.clone();
-            let peers_mutex = self.peers.clone();
-            let result_sender = result_sender.clone();
-            let work_queue = self.work_queue.clone();
-            let rx = rx.resubscribe();
-            tokio::spawn(async move {
-                let tracker = match get_peers(&torrent, addr).await {
-                    Some(a) => a,
-                    None => return
-                };
-                println!("Connected to tracker");
-                for peer in tracker.announce.peers {
-                    if peers_mutex.lock().unwrap().insert(peer) {
-                        let torrent = torrent.clone();
-                        let result_sender = result_sender.clone();
-                        let work_queue = work_queue.clone();
-                        let rx = rx.resubscribe();
+        self.add_peers(self.initial_peers.clone(), &result_sender, &rx);
 
+        let trackers = TrackerManager::new(&self.torrent);
+        let tracker_task = tokio::spawn(trackers.run(self.torrent.info_hash(), listener.port, self.stats.clone(), rx.resubscribe(), peer_sender));
+
+        let mut completed = self.work_queue.completed_count();
+        let mut choke_interval = interval(CHOKE_INTERVAL);
+        let mut resume_interval = interval(RESUME_INTERVAL);
+
+        loop {
+            tokio::select! {
+                Some(peers) = peer_receiver.recv() => self.add_peers(peers, &result_sender, &rx),
+                Some(peer) = incoming.recv() => {
+                    if self.peers.lock().unwrap().insert(peer.addr) {
+                        let (mut status, handle) = Peer::new(peer.addr, self.context(), result_sender.clone(), rx.resubscribe(), self.status());
+                        self.handles.lock().unwrap().insert(peer.addr, handle);
                         tokio::spawn(async move {
-                            let mut status = Peer::new(peer, work_queue, result_sender, rx, Status::Leeching, torrent);
-                            status.connect().await
+                            status.accept(peer.socket, &peer.handshake).await
                         });
                     }
-                }
-            });
+                },
+                Some(j) = result_receiver.recv() => {
+                    if self.work_queue.is_complete(j.piece_index) {
+                        continue;
+                    }
+                    //Written before it is marked complete, peers are only served complete pieces
+                    self.storage.write_piece(&self.torrent, j.piece_index, &j.data).expect("Could not write piece");
+                    self.work_queue.complete(j.piece_index);
+                    completed += 1;
+
+                    println!("Completed piece: {}. {:.2}%", j.piece_index, completed as f64 / self.torrent.num_pieces as f64 * 100.0);
+
+                    let piece_len = j.data.len() as u64;
+                    self.stats.downloaded.fetch_add(piece_len, Ordering::Relaxed);
+                    self.stats.left.fetch_sub(piece_len, Ordering::Relaxed);
+                    self.broadcast(PeerCommand::Have(j.piece_index));
+
+                    if completed == self.torrent.num_pieces {
+                        println!("Finished, seeding");
+                        tx.send(Status::Seeding).unwrap();
+                    }
+                },
+                _ = choke_interval.tick() => self.choke_round(),
+                _ = resume_interval.tick() => self.save_resume(),
+                _ = tokio::signal::ctrl_c() => break
+            }
+        }
+
+        let _ = tx.send(Status::Closing);
+        self.save_resume();
+        //Give the trackers a chance to receive the stopped event
+        let _ = timeout(STOPPED_TIMEOUT, tracker_task).await;
+    }
+
+    fn add_peers(&self, peers: Vec<Address>, result_sender: &Sender<PieceWrite>, rx: &broadcast::Receiver<Status>) {
+        for peer in peers {
+            if self.peers.lock().unwrap().insert(peer) {
+                let (mut status, handle) = Peer::new(peer, self.context(), result_sender.clone(), rx.resubscribe(), self.status());
+                self.handles.lock().unwrap().insert(peer, handle);
+                tokio::spawn(async move {
+                    status.connect().await
+                });
+            }
+        }
+    }
 
-            
+    fn choke_round(&self) {
+        //Peers whose task ended dropped their command receiver
+        self.prune(|handle| !handle.commands.is_closed());
+
+        let mut handles = self.handles.lock().unwrap();
+        let candidates: Vec<_> = handles.values_mut().map(|handle| handle.candidate(CHOKE_INTERVAL)).collect();
+        let seeding = matches!(self.status(), Status::Seeding);
+        let unchoked = self.choker.lock().unwrap().unchoke(&candidates, seeding);
+
+        for (addr, handle) in handles.iter() {
+            let command = if unchoked.contains(addr) {PeerCommand::Unchoke} else {PeerCommand::Choke};
+            let _ = handle.commands.send(command);
         }
+    }
+
+    fn broadcast(&self, command: PeerCommand) {
+        self.prune(|handle| handle.commands.send(command.clone()).is_ok());
+    }
 
-        let mut completed = 0;
-
-        for _ in 0..self.torrent.num_pieces {
-            let j = result_receiver.recv().await.unwrap();
-            self.work_queue.complete(j.piece_index);
-            completed += 1;
-    
-            println!("Completed piece: {}. {:.2}%", j.piece_index, completed as f64 / self.torrent.num_pieces as f64 * 100.0);
-    
-            self.files.write_to_file(&self.torrent, j.piece_index, j.data);
-    
-            if completed == self.torrent.num_pieces {
-                println!("Finished");
-                tx.send(Status::Closing).unwrap();
+    //Drops the handles keep rejects and forgets their addresses, so a re-announce can dial them again
+    fn prune(&self, mut keep: impl FnMut(&PeerHandle) -> bool) {
+        let mut closed: Vec<Address> = Vec::new();
+        self.handles.lock().unwrap().retain(|addr, handle| {
+            let open = keep(handle);
+            if !open {
+                closed.push(*addr);
             }
+            open
+        });
+        let mut peers = self.peers.lock().unwrap();
+        for addr in closed {
+            peers.remove(&addr);
+        }
+    }
+
+    fn save_resume(&self) {
+        //The file states recorded below have to cover everything the bitfield claims
+        if let Err(error) = self.storage.flush() {
+            println!("Could not flush storage: {}", error);
+            return
+        }
+        let resume = ResumeData {
+            info_hash: self.torrent.info_hash(),
+            bitfield: self.work_queue.bitfield(self.torrent.num_pieces),
+            files: self.storage.file_states(),
+            blocks: self.work_queue.partial_blocks(),
+            peers: self.handles.lock().unwrap().values()
+                .filter(|handle| handle.stats.reachable.load(Ordering::Relaxed))
+                .map(|handle| handle.addr)
+                .take(resume::MAX_PEERS)
+                .collect(),
+            uploaded: self.stats.uploaded.load(Ordering::Relaxed),
+            downloaded: self.stats.downloaded.load(Ordering::Relaxed)
+        };
+        if let Err(error) = resume.save(&self.resume_path) {
+            println!("Could not save resume data: {}", error);
+        }
+    }
+
+    fn context(&self) -> TorrentContext {
+        TorrentContext {
+            work_queue: self.work_queue.clone(),
+            storage: self.storage.clone(),
+            stats: self.stats.clone(),
+            torrent: self.torrent.clone(),
+            hasher: self.hasher.clone(),
+            suppress_haves: self.suppress_haves,
+            request_queue: self.request_queue
+        }
+    }
+
+    fn status(&self) -> Status {
+        if self.stats.left.load(Ordering::Relaxed) == 0 {Status::Seeding} else {Status::Leeching}
+    }
+}
+
+//Hash checks data left by an earlier run and marks the good pieces complete, returns the bytes verified
+async fn recheck(torrent: &Arc<Torrent>, storage: &Arc<dyn Storage>, work_queue: &PieceQueue, hasher: &Hasher) -> u64 {
+    let pieces = (0..torrent.num_pieces).filter(|piece_index| storage.may_have_piece(torrent, *piece_index)).collect();
+    let mut verified: u64 = 0;
+    for piece_index in hasher.check_stored(torrent, storage, pieces).await {
+        work_queue.complete(piece_index);
+        verified += torrent.piece_len(piece_index as i32) as u64;
+    }
+    if verified > 0 {
+        println!("Resumed {} of {} pieces", work_queue.completed_count(), torrent.num_pieces);
+    }
+    verified
+}
+
+//Marks the pieces from the resume file complete and restores partial pieces, returns the bytes they cover
+fn restore(torrent: &Arc<Torrent>, resume: &ResumeData, work_queue: &PieceQueue) -> u64 {
+    let mut verified: u64 = 0;
+    for piece_index in 0..torrent.num_pieces {
+        let has_piece = resume.bitfield.get(piece_index / 8).map(|byte| byte & (0x80 >> (piece_index % 8)) != 0).unwrap_or(false);
+        if has_piece {
+            work_queue.complete(piece_index);
+            verified += torrent.piece_len(piece_index as i32) as u64;
         }
     }
-}
\ No newline at end of file
+    for (piece_index, block_index, data) in &resume.blocks {
+        work_queue.restore_block(*piece_index, *block_index, data.clone(), torrent);
+    }
+    println!("Resumed {} of {} pieces from resume data", work_queue.completed_count(), torrent.num_pieces);
+    verified
+}
@@ -1,16 +1,17 @@
-use tokio::{net::TcpStream, io::AsyncWriteExt};
+use std::collections::HashSet;
 
-use crate::{torrent_parser::Torrent, message::builders};
+use crate::{torrent_parser::Torrent, Address};
 
+//A piece being downloaded, shared by every peer that has it so blocks survive disconnects
 pub struct Piece {
-    pub piece_index: i32,
-    pub frequency: usize,
-    pub blocks: Option<Vec<Vec<u8>>>,
     pub length: usize,
-    
-    blocks_requested: Vec<bool>,
+
+    blocks: Vec<Vec<u8>>,
+    //Peer that sent each block, blamed if the piece fails the hash check
+    contributors: Vec<Option<Address>>,
+    //Peers with an outstanding request for each block, more than one only in endgame
+    requested: Vec<Vec<Address>>,
     completed: usize,
-    requested: usize,
 }
 
 pub struct PieceWrite {
@@ -20,53 +21,82 @@ pub struct PieceWrite {
 
 
 impl Piece {
-    pub fn new(piece_index: i32, frequency: usize, torrent: &Torrent) -> Self {
+    pub fn new(piece_index: i32, torrent: &Torrent) -> Self {
+        let length = torrent.blocks_per_piece(piece_index) as usize;
         Self {
-            length: torrent.blocks_per_piece(piece_index) as usize,
+            length,
+            blocks: vec![Vec::new(); length],
+            contributors: vec![None; length],
+            requested: vec![Vec::new(); length],
             completed: 0,
-            requested: 0,
-            piece_index,
-            blocks: None,
-            blocks_requested: vec![false; torrent.blocks_per_piece(piece_index) as usize],
-            frequency
         }
     }
 
-    pub fn get_needed(&mut self, torrent: &Torrent) -> Option<usize> {
-        //println!("bpp: {}", torrent.blocks_per_piece(self.piece_index));
-        if self.blocks.is_none() {
-            self.blocks = Some(vec![Vec::new(); torrent.blocks_per_piece(self.piece_index) as usize])
+    //Next block for addr to request. Outside endgame only blocks nobody asked for, in endgame
+    //the missing block with the fewest requesters that addr hasn't requested yet
+    pub fn next_block(&self, addr: Address, endgame: bool) -> Option<usize> {
+        let mut missing = (0..self.length).filter(|i| !self.has_block(*i));
+        if endgame {
+            missing.filter(|i| !self.requested[*i].contains(&addr)).min_by_key(|i| self.requested[*i].len())
+        } else {
+            missing.find(|i| self.requested[*i].is_empty())
         }
+    }
+
+    pub fn request(&mut self, index: usize, addr: Address) {
+        if !self.requested[index].contains(&addr) {
+            self.requested[index].push(addr);
+        }
+    }
 
-        for (i, requested) in self.blocks_requested.iter().enumerate() {
-            if !requested {
-                return Some(i);
-            }
+    //The request was rejected, dropped by a choke or its peer disconnected
+    pub fn unrequest(&mut self, index: usize, addr: Address) {
+        if let Some(requested) = self.requested.get_mut(index) {
+            requested.retain(|a| *a != addr);
         }
-        None
     }
 
-    pub fn add_block(&mut self, index: usize, data: Vec<u8>) -> bool {
-        self.blocks.as_mut().unwrap()[index] = data;
+    pub fn requesters(&self, index: usize) -> &[Address] {
+        &self.requested[index]
+    }
+
+    pub fn has_block(&self, index: usize) -> bool {
+        self.blocks.get(index).map(|block| !block.is_empty()).unwrap_or(false)
+    }
+
+    //Stores a block, true once every block is present. Duplicates and out of range blocks are dropped
+    pub fn add_block(&mut self, index: usize, data: Vec<u8>, addr: Address) -> bool {
+        if index >= self.length || self.has_block(index) || data.is_empty() {
+            return false
+        }
+        self.blocks[index] = data;
+        self.contributors[index] = Some(addr);
+        self.requested[index].clear();
         self.completed += 1;
+        self.is_complete()
+    }
+
+    //Block from the resume file, nobody to blame for it
+    pub fn restore_block(&mut self, index: usize, data: Vec<u8>) {
+        if index < self.length && !self.has_block(index) && !data.is_empty() {
+            self.blocks[index] = data;
+            self.completed += 1;
+        }
+    }
+
+    pub fn received_blocks(&self) -> Vec<(usize, Vec<u8>)> {
+        (0..self.length).filter(|i| self.has_block(*i)).map(|i| (i, self.blocks[i].clone())).collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
         self.completed == self.length
     }
 
-    pub fn is_done(&self) -> bool {
-        self.requested == self.length
+    pub fn data(&self) -> Vec<u8> {
+        self.blocks.concat()
     }
 
-    pub async fn request(&mut self, socket: &mut TcpStream, torrent: &Torrent) -> bool {
-        if let Some(block_index) = self.get_needed(torrent) {
-            let _ = socket.write_all(&builders::build_request(
-                self.piece_index, 
-                (block_index * 16384).try_into().unwrap(),
-                torrent.block_len(self.piece_index, block_index.try_into().unwrap()).try_into().unwrap(),
-            )).await;
-            self.requested += 1;
-            self.blocks_requested[block_index] = true;
-            return false;
-        }
-        true
+    pub fn contributors(&self) -> HashSet<Address> {
+        self.contributors.iter().flatten().copied().collect()
     }
-}
\ No newline at end of file
+}
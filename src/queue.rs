@@ -1,52 +1,239 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::{Mutex, Condvar};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use rand::seq::SliceRandom;
+use tokio::sync::{broadcast, watch};
+use crate::{piece::{Piece, PieceWrite}, torrent_parser::Torrent, Address};
+
+//Until this many pieces are complete any available piece is picked, so we quickly have something to trade
+const RANDOM_FIRST_PIECES: usize = 4;
+//Peers that contributed to this many pieces failing the hash check are dropped
+const MAX_HASH_FAILURES: usize = 3;
 
 pub struct PieceQueue {
-    queue: Mutex<HashMap<usize, usize>>,
-    completed: Mutex<HashSet<usize>>,
-    condvar: Condvar,
+    state: Mutex<QueueState>,
+    //Bumped whenever a pop could find new work, idle peers wait on it
+    changed: watch::Sender<u64>,
+    //Endgame blocks as they arrive, (piece, block), so the other requesters can cancel
+    blocks: broadcast::Sender<(usize, usize)>,
+}
+
+pub enum BlockResult {
+    //Already had it, or the piece isn't being downloaded
+    Ignored,
+    Added,
+    //Every block is present, the piece has to be verified
+    Completed(PieceWrite, HashSet<Address>)
+}
+
+#[derive(Default)]
+struct QueueState {
+    //Number of connected peers that have each incomplete piece
+    availability: HashMap<usize, usize>,
+    //Incomplete pieces grouped by availability, so the rarest are found without sorting
+    buckets: BTreeMap<usize, HashSet<usize>>,
+    completed: HashSet<usize>,
+    //Pieces with blocks requested or received, shared by every peer
+    in_progress: HashMap<usize, Piece>,
+    //Pieces with every block, waiting for their hash check
+    verifying: HashSet<usize>,
+    hash_failures: HashMap<Address, usize>,
+}
+
+impl QueueState {
+    fn set_availability(&mut self, item: usize, count: usize) {
+        if let Some(old) = self.availability.insert(item, count) {
+            if let Some(bucket) = self.buckets.get_mut(&old) {
+                bucket.remove(&item);
+                if bucket.is_empty() {
+                    self.buckets.remove(&old);
+                }
+            }
+        }
+        if count == 0 {
+            self.availability.remove(&item);
+        } else {
+            self.buckets.entry(count).or_default().insert(item);
+        }
+    }
+
+    fn is_started(&self, item: usize) -> bool {
+        self.in_progress.contains_key(&item) || self.verifying.contains(&item)
+    }
+
+    //Rarest piece the peer has that nobody started, ties are broken randomly so peers spread over different pieces
+    fn pop(&self, can_process: &dyn Fn(usize, &[bool]) -> bool, bitfield: &[bool]) -> Option<usize> {
+        let random_first = self.completed.len() < RANDOM_FIRST_PIECES;
+        let mut candidates: Vec<usize> = Vec::new();
+        for bucket in self.buckets.values() {
+            candidates.extend(bucket.iter().filter(|item| !self.is_started(**item) && can_process(**item, bitfield)));
+            if !candidates.is_empty() && !random_first {
+                break;
+            }
+        }
+        candidates.choose(&mut rand::thread_rng()).copied()
+    }
 }
 
 impl PieceQueue {
     pub fn new() -> Self {
         PieceQueue {
-            queue: Mutex::new(HashMap::new()),
-            completed: Mutex::new(HashSet::new()),
-            condvar: Condvar::new(),
+            state: Mutex::new(QueueState::default()),
+            changed: watch::channel(0).0,
+            blocks: broadcast::channel(256).0,
         }
     }
 
     pub fn push(&self, item: usize, frequency: usize) {
-        if self.completed.lock().unwrap().contains(&item) {
+        let mut state = self.state.lock().unwrap();
+        if state.completed.contains(&item) {
             return
         }
 
-        let mut queue = self.queue.lock().unwrap();
-        let count = queue.entry(item).or_insert(0);
-        *count += frequency;
-        self.condvar.notify_all();
+        let count = state.availability.get(&item).copied().unwrap_or(0);
+        state.set_availability(item, count + frequency);
+        drop(state);
+        self.notify();
     }
 
-    pub fn complete(&self, item: usize) -> bool {
-        self.completed.lock().unwrap().insert(item)
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changed.subscribe()
+    }
+
+    pub fn subscribe_blocks(&self) -> broadcast::Receiver<(usize, usize)> {
+        self.blocks.subscribe()
     }
 
-    pub fn pop(&self, can_process: &dyn Fn(usize, &[bool]) -> bool, bitfield: &[bool]) -> (usize, usize) {
-        let mut queue = self.queue.lock().unwrap();
-        
-        loop {
-            let mut count_vec: Vec<_> = queue.clone().into_iter().collect();
-            count_vec.sort_by(|a, b| b.1.cmp(&a.1));
+    fn notify(&self) {
+        self.changed.send_modify(|version| *version += 1);
+    }
 
-            for (item, _) in count_vec.iter() {
-                if can_process(*item, bitfield) {
-                    if let Some((_, freq)) = queue.remove_entry(item) {
-                        return (*item, freq)
-                    }
-                }
+    //A peer holding these pieces went away
+    pub fn remove(&self, items: &[bool]) {
+        let mut state = self.state.lock().unwrap();
+        for (item, _) in items.iter().enumerate().filter(|(_, has)| **has) {
+            if let Some(count) = state.availability.get(&item).copied() {
+                state.set_availability(item, count - 1);
+            }
+        }
+    }
+
+    //Next block for the peer: unrequested blocks of started pieces first, then a new piece,
+    //and in endgame, once every remaining piece is started, duplicates of blocks already requested
+    pub fn request_block(&self, can_process: &dyn Fn(usize, &[bool]) -> bool, bitfield: &[bool], addr: Address, torrent: &Torrent) -> Option<(usize, usize)> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        let mut next = state.in_progress.iter()
+            .filter(|(item, _)| can_process(**item, bitfield))
+            .find_map(|(item, piece)| Some((*item, piece.next_block(addr, false)?)));
+
+        if next.is_none() {
+            if let Some(item) = state.pop(can_process, bitfield) {
+                let piece = Piece::new(item as i32, torrent);
+                next = piece.next_block(addr, false).map(|block| (item, block));
+                state.in_progress.insert(item, piece);
             }
-            
-            queue = self.condvar.wait(queue).unwrap();
         }
+
+        if next.is_none() && !state.buckets.values().flatten().any(|item| !state.is_started(*item)) {
+            next = state.in_progress.iter()
+                .filter(|(item, _)| can_process(**item, bitfield))
+                .filter_map(|(item, piece)| Some((*item, piece.next_block(addr, true)?)))
+                .min_by_key(|(item, block)| state.in_progress[item].requesters(*block).len());
+        }
+
+        let (item, block) = next?;
+        state.in_progress.get_mut(&item)?.request(block, addr);
+        Some((item, block))
     }
-}
\ No newline at end of file
+
+    //The request was rejected, dropped by a choke or its peer went away, someone else can ask for the block
+    pub fn unrequest(&self, item: usize, block: usize, addr: Address) {
+        if let Some(piece) = self.state.lock().unwrap().in_progress.get_mut(&item) {
+            piece.unrequest(block, addr);
+        }
+        self.notify();
+    }
+
+    pub fn add_block(&self, item: usize, block: usize, data: Vec<u8>, addr: Address) -> BlockResult {
+        let mut state = self.state.lock().unwrap();
+        let piece = match state.in_progress.get_mut(&item) {
+            Some(piece) => piece,
+            None => return BlockResult::Ignored
+        };
+        if piece.has_block(block) {
+            return BlockResult::Ignored
+        }
+        //Everyone else who asked for this block can cancel
+        if piece.requesters(block).iter().any(|a| *a != addr) {
+            let _ = self.blocks.send((item, block));
+        }
+        if !piece.add_block(block, data, addr) {
+            return BlockResult::Added
+        }
+
+        let piece = state.in_progress.remove(&item).unwrap();
+        state.verifying.insert(item);
+        let piece_write = PieceWrite {
+            data: piece.data(),
+            piece_index: item,
+        };
+        BlockResult::Completed(piece_write, piece.contributors())
+    }
+
+    //Received blocks of unfinished pieces, (piece, block, data)
+    pub fn partial_blocks(&self) -> Vec<(usize, usize, Vec<u8>)> {
+        let state = self.state.lock().unwrap();
+        state.in_progress.iter()
+            .flat_map(|(item, piece)| piece.received_blocks().into_iter().map(|(block, data)| (*item, block, data)))
+            .collect()
+    }
+
+    pub fn restore_block(&self, item: usize, block: usize, data: Vec<u8>, torrent: &Torrent) {
+        let mut state = self.state.lock().unwrap();
+        if item >= torrent.num_pieces || state.completed.contains(&item) {
+            return
+        }
+        let piece = state.in_progress.entry(item).or_insert_with(|| Piece::new(item as i32, torrent));
+        piece.restore_block(block, data);
+    }
+
+    //The piece failed its hash check, all of it is downloaded again and the contributors get blamed
+    pub fn fail(&self, item: usize, contributors: &HashSet<Address>) {
+        let mut state = self.state.lock().unwrap();
+        state.verifying.remove(&item);
+        for addr in contributors {
+            *state.hash_failures.entry(*addr).or_insert(0) += 1;
+        }
+        drop(state);
+        self.notify();
+    }
+
+    pub fn is_banned(&self, addr: Address) -> bool {
+        self.state.lock().unwrap().hash_failures.get(&addr).copied().unwrap_or(0) >= MAX_HASH_FAILURES
+    }
+
+    pub fn complete(&self, item: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.set_availability(item, 0);
+        state.in_progress.remove(&item);
+        state.verifying.remove(&item);
+        state.completed.insert(item)
+    }
+
+    pub fn is_complete(&self, item: usize) -> bool {
+        self.state.lock().unwrap().completed.contains(&item)
+    }
+
+    pub fn completed_count(&self) -> usize {
+        self.state.lock().unwrap().completed.len()
+    }
+
+    pub fn bitfield(&self, num_pieces: usize) -> Vec<u8> {
+        let mut bitfield = vec![0; num_pieces.div_ceil(8)];
+        for item in self.state.lock().unwrap().completed.iter() {
+            bitfield[item / 8] |= 0x80 >> (item % 8);
+        }
+        bitfield
+    }
+}
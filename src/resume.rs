@@ -0,0 +1,182 @@
+use std::{fs, io, path::Path};
+use bencode::{Bee, BeeValue};
+use crate::Address;
+
+//Most peers kept for the next run
+pub const MAX_PEERS: usize = 50;
+
+//State saved next to the download so a restart can skip the full recheck
+pub struct ResumeData {
+    pub info_hash: Vec<u8>,
+    pub bitfield: Vec<u8>,
+    //Storage::file_states when this was written
+    pub files: Vec<(u64, u128)>,
+    //Blocks of unfinished pieces, (piece, block, data)
+    pub blocks: Vec<(usize, usize, Vec<u8>)>,
+    pub peers: Vec<Address>,
+    pub uploaded: u64,
+    pub downloaded: u64
+}
+
+impl ResumeData {
+    pub fn load(path: &Path, info_hash: &[u8]) -> Option<ResumeData> {
+        let bytes = fs::read(path).ok()?;
+        let resume = BeeValue::from_bytes(&bytes);
+        let dict = resume.get_dict()?;
+        if dict.get("info-hash")?.get_raw()? != info_hash {
+            return None
+        }
+
+        let list = |key: &str| dict.get(key).and_then(|list| list.get_list()).map(|list| list.to_vec()).unwrap_or_default();
+
+        let files = list("files").iter()
+            .map(|file| Some((get_u64(file, "size")?, u128::try_from(get_value(file, "mtime")?.get_int()?).ok()?)))
+            .collect::<Option<Vec<_>>>()?;
+        let blocks = list("blocks").iter()
+            .filter_map(|block| Some((get_u64(block, "piece")? as usize, get_u64(block, "block")? as usize, get_value(block, "data")?.get_raw()?)))
+            .collect();
+        let peers = list("peers").iter()
+            .filter_map(|peer| peer.get_string()?.parse().ok())
+            .take(MAX_PEERS)
+            .collect();
+
+        Some(ResumeData {
+            info_hash: info_hash.to_vec(),
+            bitfield: dict.get("bitfield")?.get_raw()?,
+            files,
+            blocks,
+            peers,
+            uploaded: get_u64(&resume, "uploaded").unwrap_or(0),
+            downloaded: get_u64(&resume, "downloaded").unwrap_or(0)
+        })
+    }
+
+    //Written to a temporary file first so a crash mid-write never leaves a corrupt resume file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temp = path.with_extension("resume.tmp");
+        fs::write(&temp, self.encode())?;
+        fs::rename(temp, path)
+    }
+
+    //Keys have to be in sorted order
+    fn encode(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.push(b'd');
+        put_bytes(&mut buf, b"bitfield");
+        put_bytes(&mut buf, &self.bitfield);
+        put_bytes(&mut buf, b"blocks");
+        buf.push(b'l');
+        for (piece, block, data) in &self.blocks {
+            buf.push(b'd');
+            put_bytes(&mut buf, b"block");
+            put_int(&mut buf, *block as u128);
+            put_bytes(&mut buf, b"data");
+            put_bytes(&mut buf, data);
+            put_bytes(&mut buf, b"piece");
+            put_int(&mut buf, *piece as u128);
+            buf.push(b'e');
+        }
+        buf.push(b'e');
+        put_bytes(&mut buf, b"downloaded");
+        put_int(&mut buf, self.downloaded as u128);
+        put_bytes(&mut buf, b"files");
+        buf.push(b'l');
+        for (size, mtime) in &self.files {
+            buf.push(b'd');
+            put_bytes(&mut buf, b"mtime");
+            put_int(&mut buf, *mtime);
+            put_bytes(&mut buf, b"size");
+            put_int(&mut buf, *size as u128);
+            buf.push(b'e');
+        }
+        buf.push(b'e');
+        put_bytes(&mut buf, b"info-hash");
+        put_bytes(&mut buf, &self.info_hash);
+        put_bytes(&mut buf, b"peers");
+        buf.push(b'l');
+        for peer in &self.peers {
+            put_bytes(&mut buf, peer.to_string().as_bytes());
+        }
+        buf.push(b'e');
+        put_bytes(&mut buf, b"uploaded");
+        put_int(&mut buf, self.uploaded as u128);
+        buf.push(b'e');
+        buf
+    }
+}
+
+fn get_value(bee: &Bee, key: &str) -> Option<Bee> {
+    bee.get_dict()?.get(key).cloned()
+}
+
+fn get_u64(bee: &Bee, key: &str) -> Option<u64> {
+    u64::try_from(get_value(bee, key)?.get_int()?).ok()
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_int(buf: &mut Vec<u8>, value: u128) {
+    buf.extend_from_slice(format!("i{}e", value).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use super::ResumeData;
+
+    fn resume() -> ResumeData {
+        ResumeData {
+            info_hash: vec![7; 20],
+            bitfield: vec![0b1010_0000, 0x01],
+            files: vec![(65536, 1_700_000_000_123_456_789), (0, 0)],
+            blocks: vec![(3, 0, vec![1, 2, 3]), (3, 2, vec![4; 16384])],
+            peers: vec!["10.0.0.1:6881".parse().unwrap(), "[::1]:51413".parse().unwrap()],
+            uploaded: 12345,
+            downloaded: 67890
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = env::temp_dir().join(format!("resume-test-{}.resume", process::id()));
+        let saved = resume();
+        saved.save(&path).unwrap();
+        let loaded = ResumeData::load(&path, &saved.info_hash);
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.info_hash, saved.info_hash);
+        assert_eq!(loaded.bitfield, saved.bitfield);
+        assert_eq!(loaded.files, saved.files);
+        assert_eq!(loaded.blocks, saved.blocks);
+        assert_eq!(loaded.peers, saved.peers);
+        assert_eq!(loaded.uploaded, saved.uploaded);
+        assert_eq!(loaded.downloaded, saved.downloaded);
+    }
+
+    #[test]
+    fn other_info_hash_is_ignored() {
+        let path = env::temp_dir().join(format!("resume-test-other-{}.resume", process::id()));
+        resume().save(&path).unwrap();
+        let loaded = ResumeData::load(&path, &[8; 20]);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_none());
+    }
+
+    //Bencode dictionaries must have their keys sorted, the encoder writes them by hand
+    #[test]
+    fn keys_are_sorted() {
+        let encoded = resume().encode();
+        let position = |key: &str| {
+            let key = format!("{}:{}", key.len(), key);
+            encoded.windows(key.len()).position(|window| window == key.as_bytes()).unwrap()
+        };
+        let top_level = ["bitfield", "blocks", "downloaded", "files", "info-hash", "peers", "uploaded"];
+        assert!(top_level.windows(2).all(|keys| keys[0] < keys[1] && position(keys[0]) < position(keys[1])));
+        assert!(position("block") < position("data") && position("data") < position("piece"));
+        assert!(position("mtime") < position("size"));
+    }
+}
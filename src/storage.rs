@@ -0,0 +1,145 @@
+use std::{io, path::Path};
+use sha1::{Digest, Sha1};
+use crate::torrent_parser::Torrent;
+
+//Where a download's data lives. TorrentFiles is the default, anything else can be handed to Download::new_with_storage
+pub trait Storage: Send + Sync {
+    //None if the data isn't there (yet)
+    fn read_block(&self, torrent: &Torrent, piece_index: usize, begin: usize, length: usize) -> Option<Vec<u8>>;
+
+    fn write_piece(&self, torrent: &Torrent, piece_index: usize, data: &[u8]) -> io::Result<()>;
+
+    //SHA-1 of the stored piece, backends that can hash without copying the data out should override it
+    fn hash_piece(&self, torrent: &Torrent, piece_index: usize) -> Option<Vec<u8>> {
+        let data = self.read_block(torrent, piece_index, 0, torrent.piece_len(piece_index as i32) as usize)?;
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        Some(hasher.finalize().to_vec())
+    }
+
+    //Makes every written piece durable, called before the resume file is saved
+    fn flush(&self) -> io::Result<()>;
+
+    fn move_to(&self, dir: &Path) -> io::Result<()>;
+
+    fn delete(&self) -> io::Result<()>;
+
+    //Whether the piece may hold data from an earlier run and is worth rechecking
+    fn may_have_piece(&self, _torrent: &Torrent, _piece_index: usize) -> bool {
+        true
+    }
+
+    //(size, mtime in nanoseconds) of every file, the resume file is only trusted while these stay the same
+    fn file_states(&self) -> Vec<(u64, u128)>;
+}
+
+//Keeps the whole torrent in one buffer, for tests
+#[cfg(test)]
+pub struct MemoryStorage {
+    data: std::sync::Mutex<Vec<u8>>
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    pub fn new(torrent: &Torrent) -> Self {
+        Self {
+            data: std::sync::Mutex::new(vec![0; torrent.size as usize])
+        }
+    }
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn read_block(&self, torrent: &Torrent, piece_index: usize, begin: usize, length: usize) -> Option<Vec<u8>> {
+        let start = piece_index * torrent.piece_len as usize + begin;
+        self.data.lock().unwrap().get(start..start + length).map(|block| block.to_vec())
+    }
+
+    fn write_piece(&self, torrent: &Torrent, piece_index: usize, data: &[u8]) -> io::Result<()> {
+        let start = piece_index * torrent.piece_len as usize;
+        let mut buffer = self.data.lock().unwrap();
+        match buffer.get_mut(start..start + data.len()) {
+            Some(piece) => {
+                piece.copy_from_slice(data);
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "piece out of range"))
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_to(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self) -> io::Result<()> {
+        self.data.lock().unwrap().fill(0);
+        Ok(())
+    }
+
+    fn file_states(&self) -> Vec<(u64, u128)> {
+        vec![(self.data.lock().unwrap().len() as u64, 0)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use sha1::{Digest, Sha1};
+    use crate::{hasher::Hasher, torrent_parser::{test_torrent, Torrent}};
+    use super::{MemoryStorage, Storage};
+
+    const PIECE_LEN: usize = 32768;
+
+    //Single file torrent with the real hashes of data
+    fn torrent(data: &[u8]) -> Torrent {
+        let pieces: Vec<u8> = data.chunks(PIECE_LEN).flat_map(|piece| Sha1::digest(piece).to_vec()).collect();
+        test_torrent("test", &format!("6:lengthi{}e", data.len()), PIECE_LEN as i128, &pieces)
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_back_written_pieces() {
+        let data = data(2 * PIECE_LEN + 1000);
+        let torrent = torrent(&data);
+        let storage = MemoryStorage::new(&torrent);
+        for (piece_index, piece) in data.chunks(PIECE_LEN).enumerate() {
+            storage.write_piece(&torrent, piece_index, piece).unwrap();
+        }
+
+        assert_eq!(storage.read_block(&torrent, 1, 16384, 16384).unwrap(), data[PIECE_LEN + 16384..2 * PIECE_LEN]);
+        assert_eq!(storage.read_block(&torrent, 2, 0, 1000).unwrap(), data[2 * PIECE_LEN..]);
+        assert!(storage.read_block(&torrent, 2, 0, 1001).is_none());
+        assert!(storage.write_piece(&torrent, 3, &[0]).is_err());
+    }
+
+    #[test]
+    fn hash_matches_torrent() {
+        let data = data(PIECE_LEN + 5);
+        let torrent = torrent(&data);
+        let storage = MemoryStorage::new(&torrent);
+        storage.write_piece(&torrent, 1, &data[PIECE_LEN..]).unwrap();
+
+        assert_ne!(storage.hash_piece(&torrent, 0).unwrap(), torrent.hashes[0]);
+        assert_eq!(storage.hash_piece(&torrent, 1).unwrap(), torrent.hashes[1]);
+    }
+
+    #[tokio::test]
+    async fn recheck_finds_stored_pieces() {
+        let data = data(4 * PIECE_LEN);
+        let torrent = Arc::new(torrent(&data));
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new(&torrent));
+        for piece_index in [0, 2, 3] {
+            storage.write_piece(&torrent, piece_index, &data[piece_index * PIECE_LEN..(piece_index + 1) * PIECE_LEN]).unwrap();
+        }
+
+        let good = Hasher::new(2).check_stored(&torrent, &storage, (0..4).collect()).await;
+        assert_eq!(good, vec![0, 2, 3]);
+    }
+}
@@ -1,4 +1,4 @@
-use bencode::Bee;
+use bencode::{Bee, BeeValue};
 use sha1::{Sha1, Digest};
 
 #[derive(Clone)]
@@ -20,6 +20,24 @@ impl Torrent {
             hashes: torrent["info"]["pieces"].get_raw().unwrap().chunks(20).map(|a| a.to_vec()).collect()
         }
     }
+
+    pub fn from_info(info: &[u8], trackers: &[String]) -> Torrent {
+        let mut torrent: Vec<u8> = Vec::new();
+        torrent.push(b'd');
+        if let Some(tracker) = trackers.first() {
+            torrent.extend_from_slice(format!("8:announce{}:{}", tracker.len(), tracker).as_bytes());
+        }
+        torrent.extend_from_slice(b"13:announce-listl");
+        for tracker in trackers {
+            torrent.extend_from_slice(format!("l{}:{}e", tracker.len(), tracker).as_bytes());
+        }
+        torrent.push(b'e');
+        torrent.extend_from_slice(b"4:info");
+        torrent.extend_from_slice(info);
+        torrent.push(b'e');
+
+        Torrent::new(&BeeValue::from_bytes(&torrent))
+    }
 }
 
 impl Torrent {
@@ -29,26 +47,30 @@ impl Torrent {
         let result: Vec<u8> = hasher.finalize().to_vec();
         result
     }
+    //Whether hash is the SHA-1 the torrent lists for the piece
+    pub fn piece_matches(&self, piece_index: usize, hash: &[u8]) -> bool {
+        self.hashes.get(piece_index).is_some_and(|expected| expected == hash)
+    }
+
     pub fn piece_len(&self, piece_index: i32) -> i32 {
-        let total_len = self.size;
-        let piece_len: i128 = self.piece_len.try_into().unwrap();
-        let last_piece_length: i32 = (total_len % piece_len).try_into().unwrap();
-        let last_piece_index = total_len/piece_len;
-    
-        if last_piece_index == piece_index.try_into().unwrap() {last_piece_length} else {piece_len.try_into().unwrap()}
-    
+        let piece_len: i128 = self.piece_len.into();
+        let last_piece_index: i32 = (self.num_pieces as i32) - 1;
+        //The last piece holds whatever is left, a full piece when the size is an exact multiple
+        if piece_index == last_piece_index {
+            (self.size - piece_len * last_piece_index as i128).try_into().unwrap()
+        } else {
+            self.piece_len
+        }
     }
     
     pub fn blocks_per_piece(&self, piece_index: i32) -> i32 {
         let piece_length = self.piece_len(piece_index);
-        piece_length / 16384
+        (piece_length + 16383) / 16384
     }
     
     pub fn block_len(&self, piece_index: i32, block_index: i32) -> i32 {
         let piece_length = self.piece_len(piece_index);
-        let last_piece_length = piece_length % 16384;
-        let last_piece_index = piece_length / 16384;
-        if block_index == last_piece_index {last_piece_length} else {16384}
+        (piece_length - block_index * 16384).min(16384)
     }
 }
 
@@ -61,3 +83,130 @@ pub fn size(torrent: &Bee) -> i128 {
     let test = binding.iter().map(|a| a["length"].get_int().unwrap()).reduce(|a, b| a + b).unwrap();
     test
 }
+
+//Test torrent from the bencoded length or files entry of its info dict and the concatenated piece hashes
+#[cfg(test)]
+pub fn test_torrent(name: &str, files: &str, piece_len: i128, pieces: &[u8]) -> Torrent {
+    let mut torrent = format!("d4:infod{}4:name{}:{}12:piece lengthi{}e6:pieces{}:", files, name.len(), name, piece_len, pieces.len()).into_bytes();
+    torrent.extend_from_slice(pieces);
+    torrent.extend_from_slice(b"ee");
+    Torrent::new(&BeeValue::from_bytes(&torrent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_torrent, Torrent};
+
+    const BLOCK: i128 = 16384;
+
+    fn info(files: &str, piece_len: i128, size: i128) -> Torrent {
+        let num_pieces = (size + piece_len - 1) / piece_len;
+        test_torrent("test", files, piece_len, &vec![b'a'; num_pieces as usize * 20])
+    }
+
+    fn single_file(piece_len: i128, length: i128) -> Torrent {
+        info(&format!("6:lengthi{}e", length), piece_len, length)
+    }
+
+    fn multi_file(piece_len: i128, lengths: &[i128]) -> Torrent {
+        let files: String = lengths.iter().map(|length| format!("d6:lengthi{}e4:pathl1:aee", length)).collect();
+        info(&format!("5:filesl{}e", files), piece_len, lengths.iter().sum())
+    }
+
+    //Every piece and block length, checked against the total size
+    fn assert_geometry(torrent: &Torrent) {
+        let mut total: i128 = 0;
+        for piece in 0..torrent.num_pieces as i32 {
+            let piece_len = torrent.piece_len(piece);
+            assert!(piece_len > 0 && piece_len <= torrent.piece_len, "piece {} has length {}", piece, piece_len);
+
+            let blocks = torrent.blocks_per_piece(piece);
+            let block_lens: Vec<i32> = (0..blocks).map(|block| torrent.block_len(piece, block)).collect();
+            assert!(block_lens.iter().all(|len| *len > 0 && *len as i128 <= BLOCK), "piece {} blocks {:?}", piece, block_lens);
+            assert!(block_lens[..block_lens.len() - 1].iter().all(|len| *len as i128 == BLOCK));
+            assert_eq!(block_lens.iter().sum::<i32>(), piece_len);
+            total += piece_len as i128;
+        }
+        assert_eq!(total, torrent.size);
+    }
+
+    #[test]
+    fn exact_multiple_of_piece_length() {
+        let torrent = single_file(4 * BLOCK, 12 * BLOCK);
+        assert_eq!(torrent.num_pieces, 3);
+        assert_eq!(torrent.piece_len(2), 4 * BLOCK as i32);
+        assert_eq!(torrent.blocks_per_piece(2), 4);
+        assert_geometry(&torrent);
+    }
+
+    #[test]
+    fn short_last_piece() {
+        let torrent = single_file(4 * BLOCK, 9 * BLOCK);
+        assert_eq!(torrent.num_pieces, 3);
+        assert_eq!(torrent.piece_len(0), 4 * BLOCK as i32);
+        assert_eq!(torrent.piece_len(2), BLOCK as i32);
+        assert_eq!(torrent.blocks_per_piece(2), 1);
+        assert_geometry(&torrent);
+    }
+
+    #[test]
+    fn short_last_block() {
+        let torrent = single_file(4 * BLOCK, 9 * BLOCK + 100);
+        assert_eq!(torrent.piece_len(2), BLOCK as i32 + 100);
+        assert_eq!(torrent.blocks_per_piece(2), 2);
+        assert_eq!(torrent.block_len(2, 0), BLOCK as i32);
+        assert_eq!(torrent.block_len(2, 1), 100);
+        assert_geometry(&torrent);
+    }
+
+    #[test]
+    fn piece_length_not_a_multiple_of_block() {
+        let torrent = single_file(BLOCK + 1000, 3 * BLOCK);
+        assert_eq!(torrent.blocks_per_piece(0), 2);
+        assert_eq!(torrent.block_len(0, 1), 1000);
+        assert_geometry(&torrent);
+    }
+
+    #[test]
+    fn smaller_than_one_block() {
+        let torrent = single_file(4 * BLOCK, 1234);
+        assert_eq!(torrent.num_pieces, 1);
+        assert_eq!(torrent.piece_len(0), 1234);
+        assert_eq!(torrent.blocks_per_piece(0), 1);
+        assert_eq!(torrent.block_len(0, 0), 1234);
+        assert_geometry(&torrent);
+    }
+
+    #[test]
+    fn single_byte() {
+        let torrent = single_file(BLOCK, 1);
+        assert_eq!(torrent.blocks_per_piece(0), 1);
+        assert_eq!(torrent.block_len(0, 0), 1);
+        assert_geometry(&torrent);
+    }
+
+    #[test]
+    fn multi_file_sizes_are_summed() {
+        let torrent = multi_file(2 * BLOCK, &[BLOCK + 5, 3 * BLOCK, 7]);
+        assert_eq!(torrent.size, 4 * BLOCK + 12);
+        assert_eq!(torrent.num_pieces, 3);
+        assert_eq!(torrent.piece_len(2), 12);
+        assert_geometry(&torrent);
+    }
+
+    #[test]
+    fn multi_file_exact_multiple() {
+        let torrent = multi_file(2 * BLOCK, &[BLOCK, BLOCK, 2 * BLOCK]);
+        assert_eq!(torrent.num_pieces, 2);
+        assert_eq!(torrent.piece_len(1), 2 * BLOCK as i32);
+        assert_geometry(&torrent);
+    }
+
+    #[test]
+    fn multi_file_smaller_than_one_block() {
+        let torrent = multi_file(BLOCK, &[10, 20, 30]);
+        assert_eq!(torrent.num_pieces, 1);
+        assert_eq!(torrent.block_len(0, 0), 60);
+        assert_geometry(&torrent);
+    }
+}
@@ -1,22 +1,33 @@
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, Ipv6Addr}, sync::{Mutex, OnceLock}, time::{Duration, Instant}};
 use bytes::{BytesMut, BufMut};
 use rand::{Rng, rngs::ThreadRng};
 use url::Url;
-use crate::{torrent_parser::Torrent, Address};
-use tokio::net::UdpSocket;
+use crate::{http_tracker, peer_id, Address};
+use tokio::{net::{lookup_host, UdpSocket}, time::timeout};
 
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+//Keeps a scrape request and its response within a typical MTU
+pub const MAX_SCRAPE_HASHES: usize = 74;
+//BEP 15 waits 15 * 2^n seconds for a response up to n = 8, over two hours, so we give up after n = 1
+const MAX_RETRANSMISSIONS: u32 = 1;
+//Deadline for an announce or a single scrape request: every wait of the connect step and then of the request itself,
+//so a dead tracker never holds up the next one for longer than the retransmissions take
+pub const TRACKER_TIMEOUT: Duration = Duration::from_secs(2 * 15 * ((1 << (MAX_RETRANSMISSIONS + 1)) - 1));
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
 
-#[derive(Debug)]
-struct Resp {
-    _action: u32,
-    _transaction_id: u32,
-    connection_id: u64
-}
+//Connection ids by tracker address, shared by announces and scrapes
+static CONNECTION_IDS: OnceLock<Mutex<HashMap<String, (u64, Instant)>>> = OnceLock::new();
 
 #[derive(Debug)]
 pub struct Announce {
     pub action: u32,
     pub transaction_id: u32,
     pub interval: u32,
+    pub min_interval: u32,
     pub leechers: u32,
     pub seeders: u32,
     pub peers: Vec<Address>
@@ -27,22 +38,60 @@ pub struct Tracker {
     pub url: String,
 }
 
-impl Announce {
-    pub fn from_buff(buf: &[u8], num_bytes: usize) -> Announce {
-        let mut peers: Vec<Address> = vec![];
-        for n in 0..(num_bytes-20)/6 {
-            let ip: [u8; 4] = buf[n*6+20..n*6+24].try_into().unwrap();
-            let peer: Address = (
-                ip,
-                u16::from_be_bytes(buf[n*6+24..n*6+26].try_into().unwrap())
-            );
-            peers.push(peer);
+#[derive(Debug, Clone)]
+pub struct Scrape {
+    pub info_hash: Vec<u8>,
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    None,
+    Completed,
+    Started,
+    Stopped
+}
+
+pub struct AnnounceRequest {
+    pub info_hash: Vec<u8>,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event
+}
+
+impl Event {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Event::None => 0,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
         }
+    }
+
+    pub fn as_str(self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Completed => Some("completed"),
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+}
+
+impl Announce {
+    pub fn from_buff(buf: &[u8], num_bytes: usize, ipv6: bool) -> Announce {
+        let peers = parse_compact_peers(&buf[20..num_bytes], ipv6);
 
         Announce { 
             action: u32::from_be_bytes(buf[0..4].try_into().unwrap()), 
             transaction_id: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
             interval: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+            min_interval: 0,
             leechers: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
             seeders: u32::from_be_bytes(buf[16..20].try_into().unwrap()),
             peers
@@ -51,124 +100,198 @@ impl Announce {
     }
 }
 
-impl Resp {
-    pub fn from_buff(buf: &[u8]) -> Resp {
-        Resp {
-            _action: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
-            _transaction_id: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
-            connection_id: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
-        }
+pub async fn get_peers(request: &AnnounceRequest, addr: String) -> Option<Tracker> {
+    if addr.starts_with("http") {
+        http_tracker::get_peers(request, addr).await
+    } else if addr.starts_with("udp") {
+        get_peers_udp(request, addr).await
+    } else {
+        None
     }
 }
 
-enum RespTypes {
-    Connect,
-    Announce
+async fn get_peers_udp(request: &AnnounceRequest, addr: String) -> Option<Tracker> {
+    let tracker = parse_udp(&addr)?;
+    let socket = connect_udp(&tracker).await?;
+
+    let mut buf = [0; 2048];
+    let num_bytes = udp_request(&socket, &tracker, ACTION_ANNOUNCE, &build_announce_req(request), &mut buf).await?;
+    if num_bytes < 20 {
+        return None
+    }
+
+    Some(Tracker {
+        announce: Announce::from_buff(&buf, num_bytes, socket.peer_addr().ok()?.is_ipv6()),
+        url: addr,
+    })
+}
+
+pub async fn scrape(info_hashes: &[Vec<u8>], addr: String) -> Option<Vec<Scrape>> {
+    if addr.starts_with("http") {
+        http_tracker::scrape(info_hashes, addr).await
+    } else if addr.starts_with("udp") {
+        scrape_udp(info_hashes, addr).await
+    } else {
+        None
+    }
 }
 
-impl RespTypes {
-    pub fn from_u32(x: u32) -> RespTypes {
-        match x {
-            0 => RespTypes::Connect,
-            1 => RespTypes::Announce,
-            _ => RespTypes::Announce,
+async fn scrape_udp(info_hashes: &[Vec<u8>], addr: String) -> Option<Vec<Scrape>> {
+    let tracker = parse_udp(&addr)?;
+    let socket = connect_udp(&tracker).await?;
+
+    let mut scrapes: Vec<Scrape> = Vec::with_capacity(info_hashes.len());
+    let mut buf = [0; 2048];
+    for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+        let num_bytes = udp_request(&socket, &tracker, ACTION_SCRAPE, &chunk.concat(), &mut buf).await?;
+        for (i, info_hash) in chunk.iter().enumerate() {
+            let offset = 8 + i * 12;
+            if offset + 12 > num_bytes {
+                break;
+            }
+            scrapes.push(Scrape {
+                info_hash: info_hash.clone(),
+                seeders: u32::from_be_bytes(buf[offset..offset+4].try_into().unwrap()),
+                completed: u32::from_be_bytes(buf[offset+4..offset+8].try_into().unwrap()),
+                leechers: u32::from_be_bytes(buf[offset+8..offset+12].try_into().unwrap()),
+            });
         }
     }
+    Some(scrapes)
+}
+
+//Binds to the address family of the tracker, IPv6 trackers answer with 18 byte peers (BEP 15)
+async fn connect_udp(tracker: &str) -> Option<UdpSocket> {
+    let addr = lookup_host(tracker).await.ok()?.next()?;
+    let local: Address = if addr.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await.ok()?;
+    socket.connect(addr).await.ok()?;
+    Some(socket)
+}
+
+//Compact peers: 4 (or 16 for IPv6) bytes of ip followed by 2 bytes of port
+pub fn parse_compact_peers(buf: &[u8], ipv6: bool) -> Vec<Address> {
+    let ip_len = if ipv6 {16} else {4};
+    buf.chunks_exact(ip_len + 2).map(|peer| {
+        let ip: IpAddr = if ipv6 {
+            IpAddr::from(<[u8; 16]>::try_from(&peer[0..16]).unwrap())
+        } else {
+            IpAddr::from(<[u8; 4]>::try_from(&peer[0..4]).unwrap())
+        };
+        Address::new(ip, u16::from_be_bytes(peer[ip_len..ip_len+2].try_into().unwrap()))
+    }).collect()
 }
 
+//Sends a request with a cached (or freshly obtained) connection id, retransmitting on the BEP 15 schedule
+async fn udp_request(socket: &UdpSocket, tracker: &str, action: u32, body: &[u8], buf: &mut [u8]) -> Option<usize> {
+    for n in 0..=MAX_RETRANSMISSIONS {
+        let connection_id = connection_id(socket, tracker, buf).await?;
+        let transaction_id = rand::thread_rng().gen::<u32>();
 
+        let mut packet = BytesMut::with_capacity(body.len() + 16);
+        packet.put_u64(connection_id);
+        packet.put_u32(action);
+        packet.put_u32(transaction_id);
+        packet.put_slice(body);
+        socket.send(&packet).await.ok()?;
 
-async fn on_socket(buf: &[u8], socket: &UdpSocket, tracker: &String, torrent: &Torrent, num_bytes: usize) -> Option<Announce> {
-    let action = RespTypes::from_u32(u32::from_be_bytes(buf[0..4].try_into().unwrap()));
-    match action {
-        RespTypes::Announce => {
-            Some(Announce::from_buff(buf, num_bytes))
-        },
-        RespTypes::Connect => {
-            let resp = Resp::from_buff(buf);
-            let _ = socket.send_to(&build_announce_req(resp.connection_id, torrent, 6881), tracker).await;
-            None
+        if let Ok(result) = timeout(retransmission_timeout(n), recv_response(socket, tracker, action, transaction_id, buf)).await {
+            return result
         }
     }
+    None
 }
 
-pub async fn get_peers(torrent: &Torrent, addr: String) -> Option<Tracker> {
-    let socket = match UdpSocket::bind("0.0.0.0:0").await {
-        Ok(n) => n,
-        Err(_) => return None 
-    };
+async fn connection_id(socket: &UdpSocket, tracker: &str, buf: &mut [u8]) -> Option<u64> {
+    let connection_ids = CONNECTION_IDS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some((connection_id, created)) = connection_ids.lock().unwrap().get(tracker) {
+        if created.elapsed() < CONNECTION_ID_TTL {
+            return Some(*connection_id)
+        }
+    }
 
-    if !addr.starts_with("udp") {
-        return None
+    for n in 0..=MAX_RETRANSMISSIONS {
+        let transaction_id = rand::thread_rng().gen::<u32>();
+        socket.send(&build_conn_req(transaction_id)).await.ok()?;
+
+        if let Ok(result) = timeout(retransmission_timeout(n), recv_response(socket, tracker, ACTION_CONNECT, transaction_id, buf)).await {
+            let num_bytes = result?;
+            if num_bytes < 16 {
+                return None
+            }
+            let connection_id = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+            connection_ids.lock().unwrap().insert(tracker.to_string(), (connection_id, Instant::now()));
+            return Some(connection_id)
+        }
     }
-    let addr = parse_udp(addr);
-    match socket.send_to(&build_conn_req(), &addr).await {
-        Ok(_) => {},
-        Err(_) => return None
-    };
-    
-    let mut buf = [0; 2048];
+    None
+}
+
+//Waits for the response matching transaction_id, ignoring stale or unrelated packets
+async fn recv_response(socket: &UdpSocket, tracker: &str, action: u32, transaction_id: u32, buf: &mut [u8]) -> Option<usize> {
     loop {
-        // Receive data into the buffer
-        let (num_bytes, _src_addr) = match socket.recv_from(&mut buf).await {
-            Ok(a) => a,
-            Err(_) => return None
-        };
+        let num_bytes = socket.recv(buf).await.ok()?;
+        if num_bytes < 8 || u32::from_be_bytes(buf[4..8].try_into().unwrap()) != transaction_id {
+            continue;
+        }
 
-        let result = on_socket(&buf, &socket, &addr, torrent, num_bytes).await;
-        if let Some(announce) = result {
-            return Some(Tracker {
-                announce,
-                url: addr.to_string(),
-            })
+        match u32::from_be_bytes(buf[0..4].try_into().unwrap()) {
+            ACTION_ERROR => {
+                println!("Tracker {} error: {}", tracker, String::from_utf8_lossy(&buf[8..num_bytes]));
+                //The error may be caused by an expired connection id
+                CONNECTION_IDS.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap().remove(tracker);
+                return None
+            },
+            a if a == action => return Some(num_bytes),
+            _ => continue
         }
     }
+}
 
+fn retransmission_timeout(n: u32) -> Duration {
+    Duration::from_secs(15 * 2u64.pow(n))
 }
 
-fn build_conn_req() -> BytesMut {
-    let mut rng: ThreadRng = rand::thread_rng();
+fn build_conn_req(transaction_id: u32) -> BytesMut {
     let mut buffer = BytesMut::with_capacity(16);
-    buffer.put_u64(0x41727101980);
-    buffer.put_u32(0);
-    buffer.put_u32(rng.gen::<u32>());
+    buffer.put_u64(PROTOCOL_ID);
+    buffer.put_u32(ACTION_CONNECT);
+    buffer.put_u32(transaction_id);
     buffer
 }
 
-fn build_announce_req(conn_id: u64, torrent: &Torrent, port: u16) -> BytesMut {
-    let mut buf = BytesMut::with_capacity(98);
+fn build_announce_req(request: &AnnounceRequest) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(82);
     let mut rng: ThreadRng = rand::thread_rng();
-    buf.put_u64(conn_id);
-    buf.put_u32(1);
-    buf.put_u32(rng.gen::<u32>());
 
-    buf.put_slice(torrent.info_hash().as_slice());
+    buf.put_slice(&request.info_hash);
 
-    //peer-id
-    buf.put_slice(b"-AT0001-");
-    buf.put_u64(rng.gen::<u64>());
-    buf.put_u32(rng.gen::<u32>());
+    buf.put_slice(peer_id());
 
-    buf.put_u64(0);
+    buf.put_u64(request.downloaded);
 
-    buf.put_u64(torrent.size.try_into().unwrap());
+    buf.put_u64(request.left);
 
-    buf.put_u64(0);
+    buf.put_u64(request.uploaded);
 
-    buf.put_u32(0);
+    buf.put_u32(request.event.to_u32());
     buf.put_u32(0);
     buf.put_u32(rng.gen::<u32>());
     buf.put_i32(-1);
-    buf.put_u16(port);
+    buf.put_u16(request.port);
     
 
     buf
 }
 
-fn parse_udp(udp: String) -> String {
-    let url = Url::parse(&udp).unwrap();
-    let host = url.host_str().unwrap();
-    let port = url.port().unwrap().to_string();
+fn parse_udp(udp: &str) -> Option<String> {
+    let url = Url::parse(udp).ok()?;
+    let host = url.host_str()?;
+    let port = url.port()?.to_string();
     let addr = format!("{}:{}", host, port);
-    addr
-}
\ No newline at end of file
+    Some(addr)
+}
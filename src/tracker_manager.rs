@@ -0,0 +1,114 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use rand::seq::SliceRandom;
+use tokio::{sync::{broadcast::{self, error::RecvError}, mpsc::Sender}, time::{sleep_until, timeout, Instant}};
+use crate::{download::Status, peers::TransferStats, torrent_parser::Torrent, tracker::{get_peers, AnnounceRequest, Event, Tracker, TRACKER_TIMEOUT}, Address};
+
+//Used when every tracker failed or the tracker sent a nonsensical interval
+const RETRY_INTERVAL: u64 = 60;
+const MIN_INTERVAL: u64 = 30;
+
+pub struct TrackerTier {
+    pub trackers: Vec<String>
+}
+
+pub struct TrackerManager {
+    pub tiers: Vec<TrackerTier>
+}
+
+impl TrackerTier {
+    //Trackers are tried in order, the first one to respond is moved to the front of the tier (BEP 12)
+    pub async fn get_peers(&mut self, request: &AnnounceRequest) -> Option<Tracker> {
+        for i in 0..self.trackers.len() {
+            if let Ok(Some(tracker)) = timeout(TRACKER_TIMEOUT, get_peers(request, self.trackers[i].clone())).await {
+                let url = self.trackers.remove(i);
+                self.trackers.insert(0, url);
+                return Some(tracker)
+            }
+        }
+        None
+    }
+}
+
+impl TrackerManager {
+    //Tiers are tried in order, a tier is only skipped when none of its trackers respond
+    pub async fn get_peers(&mut self, request: &AnnounceRequest) -> Option<Tracker> {
+        for tier in self.tiers.iter_mut() {
+            if let Some(tracker) = tier.get_peers(request).await {
+                return Some(tracker)
+            }
+        }
+        None
+    }
+
+    //Announces started, re-announces every interval and sends completed/stopped when the download changes status
+    pub async fn run(mut self, info_hash: Vec<u8>, port: u16, stats: Arc<TransferStats>, mut status_receiver: broadcast::Receiver<Status>, peer_sender: Sender<Vec<Address>>) {
+        let mut event = Event::Started;
+        let mut started = false;
+        loop {
+            let request = stats.announce_request(&info_hash, port, event);
+            let interval = match self.get_peers(&request).await {
+                Some(tracker) => {
+                    println!("Announced to tracker {}: {} seeders, {} leechers", tracker.url, tracker.announce.seeders, tracker.announce.leechers);
+                    started = true;
+                    event = Event::None;
+                    let _ = peer_sender.send(tracker.announce.peers).await;
+                    (tracker.announce.interval as u64).max(tracker.announce.min_interval as u64).max(MIN_INTERVAL)
+                },
+                None => RETRY_INTERVAL
+            };
+
+            let next_announce = Instant::now() + Duration::from_secs(interval);
+            loop {
+                tokio::select! {
+                    _ = sleep_until(next_announce) => break,
+                    status = status_receiver.recv() => match status {
+                        Ok(Status::Seeding) if started => {
+                            event = Event::Completed;
+                            break;
+                        },
+                        Ok(Status::Closing) | Err(RecvError::Closed) => {
+                            if started {
+                                self.get_peers(&stats.announce_request(&info_hash, port, Event::Stopped)).await;
+                            }
+                            return
+                        },
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut rng = rand::thread_rng();
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut tiers: Vec<TrackerTier> = Vec::new();
+        let dict = torrent.torrent.get_dict().unwrap();
+
+        if let Some(announce_list) = dict.get("announce-list").and_then(|list| list.get_list()) {
+            for tier in announce_list.iter() {
+                let mut trackers: Vec<String> = match tier.get_list() {
+                    Some(tier) => tier.iter().filter_map(|url| url.get_string()).collect(),
+                    None => continue
+                };
+                trackers.retain(|url| seen.insert(url.clone()));
+                if trackers.is_empty() {
+                    continue;
+                }
+                trackers.shuffle(&mut rng);
+                tiers.push(TrackerTier { trackers });
+            }
+        }
+
+        //announce is only used when there is no usable announce-list
+        if tiers.is_empty() {
+            if let Some(url) = dict.get("announce").and_then(|url| url.get_string()) {
+                tiers.push(TrackerTier { trackers: vec![url] });
+            }
+        }
+
+        Self {
+            tiers
+        }
+    }
+}
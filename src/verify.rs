@@ -0,0 +1,51 @@
+use std::{fs, path::Path, sync::Arc};
+use crate::{file::TorrentFiles, hasher::Hasher, storage::Storage, torrent_parser::Torrent};
+
+//Hash checks every piece of data already on disk and prints what is wrong with it, true if everything is intact
+pub async fn verify(torrent: &Arc<Torrent>, data_dir: &Path) -> bool {
+    let files = Arc::new(TorrentFiles::open(torrent, data_dir));
+    let storage: Arc<dyn Storage> = files.clone();
+    let mut is_good = vec![false; torrent.num_pieces];
+    for piece_index in Hasher::per_core().check_stored(torrent, &storage, (0..torrent.num_pieces).collect()).await {
+        is_good[piece_index] = true;
+    }
+    let bad_pieces: Vec<usize> = (0..torrent.num_pieces).filter(|piece_index| !is_good[*piece_index]).collect();
+
+    let mut intact = bad_pieces.is_empty();
+    for file_info in files.files() {
+        let size = match fs::metadata(&file_info.path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => {
+                println!("{}: missing", file_info.path.display());
+                intact = false;
+                continue;
+            }
+        };
+        if size != file_info.size as u64 {
+            println!("{}: wrong size, {} bytes instead of {}", file_info.path.display(), size, file_info.size);
+            intact = false;
+            continue;
+        }
+
+        //Pieces the file spans, empty files have none
+        let piece_len = torrent.piece_len as i128;
+        let pieces = if file_info.size == 0 {
+            0..0
+        } else {
+            (file_info.offset / piece_len) as usize..((file_info.offset + file_info.size - 1) / piece_len + 1) as usize
+        };
+        let total = pieces.len();
+        let good = pieces.filter(|piece_index| is_good[*piece_index]).count();
+        if good == total {
+            println!("{}: complete", file_info.path.display());
+        } else {
+            println!("{}: {} of {} pieces good ({:.1}%)", file_info.path.display(), good, total, good as f64 * 100.0 / total as f64);
+        }
+    }
+
+    if !bad_pieces.is_empty() {
+        println!("Bad pieces: {:?}", bad_pieces);
+    }
+    println!("{} of {} pieces good", torrent.num_pieces - bad_pieces.len(), torrent.num_pieces);
+    intact
+}